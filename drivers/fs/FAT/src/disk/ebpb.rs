@@ -1,10 +1,12 @@
+extern crate alloc;
+
+use alloc::string::{String, ToString};
 use core::fmt::Debug;
 
 /// Shortened boot signature for the EBR.
 #[allow(unused)]
 const SHORTENED_BOOT_SIGNATURE: u8 = 0x28;
 /// Extended boot signature for the EBR - permits access to certain properties.
-#[allow(unused)]
 const EXTENDED_BOOT_SIGNATURE: u8 = 0x29;
 
 // pub trait EBPB: Debug + Sized {
@@ -23,6 +25,24 @@ pub enum EBPB {
     _32(EBPB_32)
 }
 
+impl EBPB {
+    /// Returns the serial number and label of the volume, if the EBR carries them.
+    ///
+    /// Labels are padded with spaces, and `NO NAME` stands for an unlabelled volume.
+    pub fn volume_id(&self) -> Option<(u32, String)> {
+        let (signature, serial_number, volume_label) = match self {
+            EBPB::_1216(_1216) => (_1216.signature, _1216.serial_number, _1216.volume_label),
+            EBPB::_32(_32) => (_32.signature, _32.serial_number, _32.volume_label)
+        };
+        if signature != EXTENDED_BOOT_SIGNATURE {
+            return None;
+        }
+
+        let label = String::from_utf8_lossy(&volume_label).trim_end().to_string();
+        Some((serial_number, if label == "NO NAME" { String::new() } else { label }))
+    }
+}
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug)]
 #[allow(non_camel_case_types)]
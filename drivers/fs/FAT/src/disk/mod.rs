@@ -4,6 +4,8 @@ mod fsinfo;
 
 pub mod directory_entry;
 
+use alloc::format;
+use alloc::string::String;
 use alloc::vec::Vec;
 use directory_entry::{DirectoryEntryLongFileName, DirectoryEntryMetadata};
 use wakatiwai_udive::disk::DiskReader;
@@ -18,6 +20,29 @@ pub struct FAT {
 }
 
 impl FAT {
+    /// Checks if the boot sector of a volume describes a FAT file system, so that other file systems are never parsed as one.
+    pub fn is_fat(diskreader: &DiskReader) -> bool {
+        let boot_record_buffer = match diskreader.read_sector(0) {
+            Ok(ok) => ok,
+            Err(_) => {
+                return false;
+            }
+        };
+        if boot_record_buffer.len() < 512 || boot_record_buffer[510..512] != [0x55, 0xAA] {
+            return false;
+        }
+
+        // Copy the fields out of the packed structure
+        let bpb = unsafe { *(boot_record_buffer.as_ptr() as *const bpb::BPB) };
+        let (bytes_per_sector, sectors_per_cluster, reserved_sector_count, fat_count) = (
+            bpb.bytes_per_sector, bpb.sectors_per_cluster, bpb.reserved_sector_count, bpb.fat_count
+        );
+        bytes_per_sector >= 512 && bytes_per_sector.is_power_of_two() &&
+        sectors_per_cluster.is_power_of_two() &&
+        reserved_sector_count != 0 &&
+        fat_count != 0
+    }
+
     pub fn new(diskreader: &DiskReader) -> Self {
         // Read the first sector of the disk, containing the BPB and EBPB
         let boot_record_buffer = diskreader.read_sector(0).unwrap();
@@ -61,6 +86,12 @@ impl FAT {
         ret
     }
 
+    /// Returns the identity of this volume as `<serial>\n<label>`, with the serial number formatted as `XXXX-XXXX` like `blkid` does.
+    pub fn volume_id(&self) -> Option<String> {
+        let (serial_number, label) = self.ebpb.volume_id()?;
+        Some(format!("{:04X}-{:04X}\n{}", serial_number >> 16, serial_number & 0xFFFF, label))
+    }
+
     fn total_sectors(&self) -> u64 {
         if self.bpb.small_sector_count == 0 {
             self.bpb.large_sector_count as u64
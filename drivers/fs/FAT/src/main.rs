@@ -5,6 +5,10 @@ wakatiwai_udive::fs_prelude!();
 
 mod data;
 mod disk;
+#[path = "../../request.rs"]
+mod request;
+
+use request::FSRequest;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum FATType {
@@ -14,9 +18,25 @@ enum FATType {
 }
 
 fn main(args: &FSDriverArgs) -> Result<Vec<u8>, Status> {
+    // Partitions are probed with every driver, so make sure this is FAT before parsing it
+    if !disk::FAT::is_fat(&args.diskreader) {
+        return Err(Status::UNSUPPORTED);
+    }
     let fs = disk::FAT::new(&args.diskreader);
-    
-    let mut path_directories: Vec<&str> = args.path.split("/").skip(1).collect();
+
+    let (path, read) = match FSRequest::parse(args.path) {
+        FSRequest::Read(path) => (path, true),
+        FSRequest::Exists(path) => (path, false),
+        FSRequest::VolumeId => {
+            // Volumes without an identity are still FAT
+            return Ok(fs.volume_id().unwrap_or_default().into_bytes());
+        }
+        FSRequest::Unsupported => {
+            return Err(Status::UNSUPPORTED);
+        }
+    };
+
+    let mut path_directories: Vec<&str> = path.split("/").skip(1).collect();
     let file_name = path_directories.pop().unwrap();
 
     // Navigate to the correct directory
@@ -51,6 +71,10 @@ fn main(args: &FSDriverArgs) -> Result<Vec<u8>, Status> {
             if !some.is_file() {
                 return Err(Status::NOT_FOUND);
             }
+            // Checking for a file does not need its contents
+            if !read {
+                return Ok(Vec::new());
+            }
 
             let mut ret = fs.read_cluster_chain(
                 &args.diskreader,
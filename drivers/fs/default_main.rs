@@ -3,6 +3,16 @@
 
 wakatiwai_udive::fs_prelude!();
 
+mod request;
+
+use request::FSRequest;
+
 fn main(args: &FSDriverArgs) -> Result<Vec<u8>, Status> {
-    Err(Status::NOT_FOUND)
+    match FSRequest::parse(args.path) {
+        FSRequest::Read(_) => Err(Status::NOT_FOUND),
+        // Volumes of other file systems must not be claimed, so this stays unsupported until the file system is recognised
+        FSRequest::VolumeId => Err(Status::UNSUPPORTED),
+        FSRequest::Exists(_) => Err(Status::NOT_FOUND),
+        FSRequest::Unsupported => Err(Status::UNSUPPORTED)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,60 @@
+//! Requests the boot manager makes of file system drivers.
+//!
+//! This file is shared by the boot manager and the file system drivers, and is copied into new drivers by `wakatiwai-mkdriver`.
+//! `FSDriverArgs` only carries a path, so requests other than reading a file are written as a path starting with `:`.
+//! Paths of files always start with `/`, so such a request never names a file.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+
+/// The path of a request for the identity of the volume.
+pub const VOLUME_ID_PATH: &str = ":volume";
+/// The prefix of a request for whether a file exists, followed by the path of the file.
+pub const EXISTS_PATH_PREFIX: &str = ":exists:";
+
+/// A request made of a file system driver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(dead_code)]
+pub enum FSRequest<'a> {
+    /// Read the file at a path, answered with its contents.
+    Read(&'a str),
+    /// Identify the volume, answered with its UUID and label in UTF-8, separated by a newline.
+    /// The UUID is formatted as by `blkid`, and either is empty if the volume has none.
+    /// This also tells the boot manager which file system a partition holds, so it must fail with `Status::UNSUPPORTED` on other file systems.
+    VolumeId,
+    /// Check if the file at a path exists without reading it, answered with an empty buffer if it does or `Status::NOT_FOUND` if it does not.
+    Exists(&'a str),
+    /// A request this driver does not know, which must fail with `Status::UNSUPPORTED`.
+    Unsupported,
+}
+
+#[allow(dead_code)]
+impl<'a> FSRequest<'a> {
+    /// Parses the path passed to a file system driver.
+    pub fn parse(path: &'a str) -> Self {
+        if path == VOLUME_ID_PATH {
+            FSRequest::VolumeId
+        }
+        else if let Some(file_path) = path.strip_prefix(EXISTS_PATH_PREFIX) {
+            FSRequest::Exists(file_path)
+        }
+        else if path.starts_with(':') {
+            FSRequest::Unsupported
+        }
+        else {
+            FSRequest::Read(path)
+        }
+    }
+
+    /// Returns the path to pass to a file system driver to make this request.
+    pub fn to_path(&self) -> String {
+        match self {
+            FSRequest::Read(path) => String::from(*path),
+            FSRequest::VolumeId => String::from(VOLUME_ID_PATH),
+            FSRequest::Exists(path) => format!("{}{}", EXISTS_PATH_PREFIX, path),
+            FSRequest::Unsupported => String::from(":")
+        }
+    }
+}
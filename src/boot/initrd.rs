@@ -0,0 +1,138 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+
+use uefi::{guid, Guid, Handle, Identify, Status};
+use uefi::proto::device_path::DevicePath;
+use uefi_raw::protocol::device_path::DevicePathProtocol;
+use uefi_raw::protocol::media::LoadFile2Protocol;
+use uefi_raw::Boolean;
+
+use super::BootFailure;
+
+/// The vendor GUID of the device path Linux loads its initrd from.
+/// [Reference](https://github.com/torvalds/linux/blob/master/include/linux/efi.h).
+const LINUX_EFI_INITRD_MEDIA_GUID: Guid = guid!("5568e427-68fc-4f3d-ac74-ca555231cc68");
+
+/// The device path Linux loads its initrd from: a vendor media node with the initrd media GUID, followed by an end node.
+static INITRD_DEVICE_PATH: [u8; 24] = initrd_device_path();
+
+/// Builds the bytes of `INITRD_DEVICE_PATH`.
+const fn initrd_device_path() -> [u8; 24] {
+    let guid = LINUX_EFI_INITRD_MEDIA_GUID.to_bytes();
+    let mut ret = [0 as u8; 24];
+
+    // Vendor-defined media device path node, 20 bytes long
+    ret[0] = 0x04;
+    ret[1] = 0x03;
+    ret[2] = 20;
+    let mut i = 0;
+    while i < guid.len() {
+        ret[4 + i] = guid[i];
+        i += 1;
+    }
+
+    // End of entire device path node
+    ret[20] = 0x7f;
+    ret[21] = 0xff;
+    ret[22] = 4;
+    ret
+}
+
+/// The `LoadFile2` protocol serving the initrd, followed by the initrd itself.
+#[repr(C)]
+struct InitrdLoader {
+    protocol: LoadFile2Protocol,
+    data: Vec<u8>,
+}
+
+/// An initrd made available to the kernel's EFI stub, which stays available until dropped.
+pub struct InitrdDevice {
+    handle: Handle,
+    loader: Box<InitrdLoader>,
+}
+
+impl InitrdDevice {
+    /// Installs the initrd media device path with a `LoadFile2` protocol serving the given data.
+    pub fn install(data: Vec<u8>) -> Result<Self, BootFailure> {
+        let loader = Box::new(InitrdLoader {
+            protocol: LoadFile2Protocol {
+                load_file: load_initrd
+            },
+            data
+        });
+
+        unsafe {
+            let handle = match uefi::boot::install_protocol_interface(
+                None,
+                &DevicePath::GUID,
+                INITRD_DEVICE_PATH.as_ptr() as *const c_void
+            ) {
+                Ok(ok) => ok,
+                Err(err) => {
+                    return Err(BootFailure::InitrdInstallFailed(err.status()));
+                }
+            };
+            if let Err(err) = uefi::boot::install_protocol_interface(
+                Some(handle),
+                &LoadFile2Protocol::GUID,
+                &loader.protocol as *const LoadFile2Protocol as *const c_void
+            ) {
+                let _ = uefi::boot::uninstall_protocol_interface(
+                    handle,
+                    &DevicePath::GUID,
+                    INITRD_DEVICE_PATH.as_ptr() as *const c_void
+                );
+                return Err(BootFailure::InitrdInstallFailed(err.status()));
+            }
+
+            Ok(InitrdDevice { handle, loader })
+        }
+    }
+}
+
+impl Drop for InitrdDevice {
+    fn drop(&mut self) {
+        // Only reached if the booted program returns, so a failure can be ignored
+        unsafe {
+            let _ = uefi::boot::uninstall_protocol_interface(
+                self.handle,
+                &LoadFile2Protocol::GUID,
+                &self.loader.protocol as *const LoadFile2Protocol as *const c_void
+            );
+            let _ = uefi::boot::uninstall_protocol_interface(
+                self.handle,
+                &DevicePath::GUID,
+                INITRD_DEVICE_PATH.as_ptr() as *const c_void
+            );
+        }
+    }
+}
+
+/// Copies the initrd to the caller's buffer, or reports its size if the buffer is too small.
+unsafe extern "efiapi" fn load_initrd(
+    this: *mut LoadFile2Protocol,
+    file_path: *const DevicePathProtocol,
+    boot_policy: Boolean,
+    buffer_size: *mut usize,
+    buffer: *mut c_void
+) -> Status {
+    if this.is_null() || file_path.is_null() || buffer_size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    // LoadFile2 never loads boot options
+    if bool::from(boot_policy) {
+        return Status::UNSUPPORTED;
+    }
+
+    // The protocol is the first field of the loader, so the loader can be recovered from it
+    let data = &(*(this as *const InitrdLoader)).data;
+    if buffer.is_null() || *buffer_size < data.len() {
+        *buffer_size = data.len();
+        return Status::BUFFER_TOO_SMALL;
+    }
+
+    core::ptr::copy_nonoverlapping(data.as_ptr(), buffer as *mut u8, data.len());
+    *buffer_size = data.len();
+    Status::SUCCESS
+}
@@ -1,16 +1,29 @@
+mod initrd;
 mod partition;
+#[path = "../../drivers/fs/request.rs"]
+pub mod request;
+pub mod volume;
 
-use crate::wtcore::config::BootEntry;
+use crate::wtcore::config::{BootEntry, CONFIG};
+use crate::boot::initrd::InitrdDevice;
+use crate::boot::request::FSRequest;
+use crate::dev::cache::CachedDiskIoDevice;
 use crate::{dprintln, image_handle, println};
 
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use uefi::Status;
+use uefi::fs::FileSystem;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::{cstr16, CStr16, CString16, Handle, Status};
 
 use wakatiwai_udive::boot::BootDriverArgs;
 use wakatiwai_udive::disk::DiskReader;
 use wakatiwai_udive::fs::FSDriverArgs;
 use wakatiwai_udive::{wakatiwai::*, BootDriver, FSDriver};
 
+/// Directory on the bootloader's file system containing the file system drivers.
+const FS_DRIVERS_PATH: &CStr16 = cstr16!("\\EFI\\wakatiwai\\drivers\\fs");
+
 /// Possible failures that may occur when trying to boot a given entry.
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -19,65 +32,57 @@ pub enum BootFailure {
     NoPartition,
     PartitionNotFound,
     BadGPT(Status),
+    BadMBR(Status),
     DriverSearchFailed(Status),
     DriverLoadFailed(Status),
     DriverUnloadFailed(Status),
     DriverInvokeFailed(Result<Status, Status>),
     NoBootDriver,
     NoFSDriver,
+    InitrdInstallFailed(Status),
 }
 
 pub fn attempt_boot(entry: &BootEntry) -> Option<BootFailure> {
     println!("Booting \"{}\"...", entry.name);
+    let entry = &entry.expanded(&CONFIG.read().variables);
     dprintln!("{}", entry);
 
-    let partition_handle = partition::get_partition_handle(entry).unwrap();
-    dprintln!("Acquired partition handle");
-
-    // Acquire FS driver
-    let mut fs_driver: FSDriver;
-    match get_fs_driver(&entry.fstype) {
-        Ok(None) => {
-            return Some(BootFailure::NoFSDriver);
-        }
-        Ok(Some(some)) => {
-            dprintln!("Acquired {} file system driver", entry.fstype);
-            fs_driver = some;
-            let fs_load_status = fs_driver.load();
-            if fs_load_status.is_error() {
-                return Some(BootFailure::DriverLoadFailed(fs_load_status));
-            }
+    // FS shenanigans
+    let buffer: Vec<u8>;
+    match read_file(entry, &entry.path) {
+        Ok(ok) => {
+            buffer = ok;
         }
         Err(err) => {
-            return Some(BootFailure::DriverSearchFailed(err))
+            return Some(err);
         }
     }
 
-    // // FS shenanigans
-    let buffer: Vec<u8>;
-    match fs_driver.invoke(&mut FSDriverArgs {
-        path:       &entry.path,
-        diskreader: DiskReader::new(
-            &partition_handle,
-            unsafe {
-                uefi::boot::open_protocol(
-                    uefi::boot::OpenProtocolParams {
-                        handle: partition_handle,
-                        agent: image_handle!(),
-                        controller: None,
-                    },
-                    uefi::boot::OpenProtocolAttributes::GetProtocol
-                ).unwrap()
-            },
-            0
-        )
-    }) {
-        Ok(ok) => {
-            dprintln!("Successfully read {}", entry.path);
-            buffer = ok.to_vec();
+    // Concatenate the initrds and serve them to the kernel, which must stay possible until it has booted
+    let _initrd_device: Option<InitrdDevice>;
+    if entry.initrd.is_empty() {
+        _initrd_device = None;
+    }
+    else {
+        let mut initrd_buffer: Vec<u8> = Vec::new();
+        for initrd in entry.initrd.iter() {
+            match initrd.read(entry) {
+                Ok(ok) => {
+                    dprintln!("Read initrd {} ({} bytes)", initrd.path(), ok.len());
+                    initrd_buffer.extend_from_slice(&ok);
+                }
+                Err(err) => {
+                    return Some(err);
+                }
+            }
         }
-        Err(err) => {
-            return Some(BootFailure::DriverInvokeFailed(err))
+        match InitrdDevice::install(initrd_buffer) {
+            Ok(ok) => {
+                _initrd_device = Some(ok);
+            }
+            Err(err) => {
+                return Some(err);
+            }
         }
     }
 
@@ -91,6 +96,22 @@ pub fn attempt_boot(entry: &BootEntry) -> Option<BootFailure> {
             }
         ) {
                 Ok(ok) => {
+                    // Hand the arguments over as the image's load options
+                    let load_options = CString16::try_from(entry.args.as_str()).unwrap_or_else(|_| CString16::new());
+                    if !entry.args.is_empty() {
+                        match uefi::boot::open_protocol_exclusive::<LoadedImage>(ok) {
+                            Ok(mut loaded_image) => unsafe {
+                                loaded_image.set_load_options(
+                                    load_options.as_ptr() as *const u8,
+                                    load_options.num_bytes() as u32
+                                );
+                            }
+                            Err(err) => {
+                                return Some(BootFailure::DriverInvokeFailed(Ok(err.status())));
+                            }
+                        }
+                    }
+
                     match uefi::boot::start_image(ok) {
                         Ok(_) => {
                             None
@@ -141,4 +162,111 @@ pub fn attempt_boot(entry: &BootEntry) -> Option<BootFailure> {
             return Some(BootFailure::DriverInvokeFailed(some));
         }
     }
-}
\ No newline at end of file
+}
+
+/// Reads a file from the partition of a given boot entry, using the entry's file system driver.
+pub fn read_file(entry: &BootEntry, path: &str) -> Result<Vec<u8>, BootFailure> {
+    let partition_handle = partition::get_partition_handle(entry)?;
+    dprintln!("Acquired partition handle");
+
+    let mut fs_driver = load_fs_driver(&entry.fstype)?;
+    let ret = invoke_fs_driver(&mut fs_driver, &partition_handle, path)?;
+    dprintln!("Successfully read {}", path);
+    Ok(ret)
+}
+
+/// Checks if a file exists on the partition of a given boot entry, without reading it unless the entry's file system driver cannot tell otherwise.
+pub fn file_exists(entry: &BootEntry, path: &str) -> Result<bool, BootFailure> {
+    let partition_handle = partition::get_partition_handle(entry)?;
+
+    let mut fs_driver = load_fs_driver(&entry.fstype)?;
+    match invoke_fs_driver(&mut fs_driver, &partition_handle, &FSRequest::Exists(path).to_path()) {
+        Ok(_) => Ok(true),
+        Err(BootFailure::DriverInvokeFailed(Ok(Status::NOT_FOUND) | Err(Status::NOT_FOUND))) => Ok(false),
+        // Drivers which do not support the request can only tell by reading the file
+        Err(BootFailure::DriverInvokeFailed(Ok(Status::UNSUPPORTED) | Err(Status::UNSUPPORTED))) => {
+            Ok(invoke_fs_driver(&mut fs_driver, &partition_handle, path).is_ok())
+        }
+        Err(err) => Err(err)
+    }
+}
+
+/// Lists the file systems which have a driver installed alongside the boot manager.
+pub fn get_fs_driver_names() -> Vec<String> {
+    let mut efifs = match uefi::boot::get_image_file_system(image_handle!()) {
+        Ok(ok) => FileSystem::new(ok),
+        Err(_) => return Vec::new()
+    };
+    let directory = match efifs.read_dir(FS_DRIVERS_PATH) {
+        Ok(ok) => ok,
+        Err(_) => {
+            dprintln!("Unable to read file system drivers directory");
+            return Vec::new();
+        }
+    };
+
+    directory.filter_map(|t| t.ok()).filter(|t| !t.is_directory()).filter_map(|file_info| {
+        // Drivers are named after their file system, e.g. FAT.efi
+        let file_name = file_info.file_name().to_string();
+        let (fstype, extension) = file_name.rsplit_once('.')?;
+        extension.eq_ignore_ascii_case("efi").then(|| fstype.to_string())
+    }).collect()
+}
+
+/// Acquires and loads the file system driver for a given file system.
+fn load_fs_driver(fstype: &str) -> Result<FSDriver, BootFailure> {
+    match get_fs_driver(fstype) {
+        Ok(None) => {
+            Err(BootFailure::NoFSDriver)
+        }
+        Ok(Some(mut some)) => {
+            dprintln!("Acquired {} file system driver", fstype);
+            let fs_load_status = some.load();
+            if fs_load_status.is_error() {
+                return Err(BootFailure::DriverLoadFailed(fs_load_status));
+            }
+            Ok(some)
+        }
+        Err(err) => {
+            Err(BootFailure::DriverSearchFailed(err))
+        }
+    }
+}
+
+/// Reads a path from the partition behind a given handle using a loaded file system driver.
+///
+/// The driver reads the partition through the block cache, unless it cannot be set up.
+fn invoke_fs_driver(fs_driver: &mut FSDriver, partition_handle: &Handle, path: &str) -> Result<Vec<u8>, BootFailure> {
+    let cached_disk_io = match CachedDiskIoDevice::install(partition_handle) {
+        Ok(ok) => Some(ok),
+        Err(err) => {
+            dprintln!("Unable to cache reads of partition handle {:#010x}: {:?}", partition_handle.as_ptr() as u64, err);
+            None
+        }
+    };
+    let disk_io = match cached_disk_io.as_ref().and_then(|t| t.open().ok()) {
+        Some(some) => some,
+        None => unsafe {
+            uefi::boot::open_protocol(
+                uefi::boot::OpenProtocolParams {
+                    handle: *partition_handle,
+                    agent: image_handle!(),
+                    controller: None,
+                },
+                uefi::boot::OpenProtocolAttributes::GetProtocol
+            ).unwrap()
+        }
+    };
+
+    match fs_driver.invoke(&mut FSDriverArgs {
+        path,
+        diskreader: DiskReader::new(partition_handle, disk_io, 0)
+    }) {
+        Ok(ok) => {
+            Ok(ok.to_vec())
+        }
+        Err(err) => {
+            Err(BootFailure::DriverInvokeFailed(err))
+        }
+    }
+}
@@ -1,23 +1,42 @@
-use alloc::string::ToString;
-use uefi::boot::{open_protocol, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol};
-use uefi::proto::device_path::text::{AllowShortcuts, DisplayOnly};
-use uefi::proto::device_path::DevicePath;
-use uefi::proto::media::disk::DiskIo;
+use uefi::proto::device_path::media::{HardDrive, PartitionSignature};
+use uefi::proto::device_path::DevicePathNodeEnum;
 use uefi::{Guid, Handle};
 use uefi_raw::protocol::block::BlockIoProtocol;
-use wakatiwai_udive::disk::DiskReader;
 
-use crate::dev::gpt::GPT;
-use crate::dev::DISK_GUID_HANDLE_MAPPING;
-use crate::image_handle;
+use crate::dev;
+use crate::dev::cache::CachedDiskReader;
+use crate::dev::gpt::{GPTEntry, GPT};
+use crate::dev::mbr::MBR;
+use crate::dev::{DISK_GUID_HANDLE_MAPPING, DISK_SIGNATURE_HANDLE_MAPPING};
 use crate::wtcore::config::BootEntry;
 
-use super::BootFailure;
+use super::{volume, BootFailure};
 
 pub fn get_partition_handle(entry: &BootEntry) -> Result<Handle, BootFailure> {
+    // A file system given by its UUID or label may be on any disk
+    if !entry.fs_uuid.is_empty() || !entry.fs_label.is_empty() {
+        return volume::get_volume_handle(entry);
+    }
+    if entry.disk_signature != 0 {
+        return get_mbr_partition_handle(entry);
+    }
+
+    // A partition given by its GUID alone may be on any disk
+    let mut disk_guid = entry.disk_guid;
+    if disk_guid == Guid::ZERO && entry.part_uuid != Guid::ZERO {
+        match dev::locate_partition(entry.part_uuid) {
+            Some(some) => {
+                disk_guid = some.0;
+            }
+            None => {
+                return Err(BootFailure::PartitionNotFound);
+            }
+        }
+    }
+
     // Acquire handle to disk from GUID
     let disk_handle: Handle;
-    match DISK_GUID_HANDLE_MAPPING.get(&entry.disk_guid) {
+    match DISK_GUID_HANDLE_MAPPING.read().get(&disk_guid) {
         Some(some) => {
             disk_handle = unsafe { Handle::from_ptr(*some as *mut core::ffi::c_void).unwrap() };
         }
@@ -28,20 +47,7 @@ pub fn get_partition_handle(entry: &BootEntry) -> Result<Handle, BootFailure> {
 
     // Read disk GPT
     let disk_gpt: GPT;
-    match GPT::read_gpt(&DiskReader::new(
-        &disk_handle,
-        unsafe {
-            open_protocol::<DiskIo>(
-                OpenProtocolParams {
-                    handle: disk_handle,
-                    agent: image_handle!(),
-                    controller: None
-                },
-                OpenProtocolAttributes::GetProtocol
-            ).unwrap()
-        },
-        0
-    )) {
+    match dev::read_handle_gpt(&disk_handle) {
         Ok(ok) => {
             disk_gpt = ok;
         }
@@ -50,53 +56,123 @@ pub fn get_partition_handle(entry: &BootEntry) -> Result<Handle, BootFailure> {
         }
     }
 
-    // Get partition guid of boot entry
-    let partition_guid: Guid;
-    match disk_gpt.entries.get(entry.partition as usize - 1) {
-        Some(some) => {
-            // Ensure that the entry is used
+    // Get the number and partition guid of the first partition selected by the boot entry
+    let (partition_number, partition_guid): (usize, Guid);
+    match disk_gpt.entries.iter().enumerate().find(|(idx, t)| is_partition_selected(entry, idx + 1, t)) {
+        Some((idx, some)) => {
+            partition_number = idx + 1;
             partition_guid = some.partition_guid;
-            if partition_guid == Guid::ZERO {
-                return Err(BootFailure::NoPartition)
-            }
         }
         None => {
-            // Fires if the partition is beyond 128
             return Err(BootFailure::NoPartition)
         }
     }
 
     // Search for the partition handle
+    find_partition_handle(disk_handle, |hard_drive| {
+        hard_drive.partition_number() == partition_number as u32 &&
+        hard_drive.partition_signature() == PartitionSignature::Guid(partition_guid)
+    })
+}
+
+/// Checks if a (used) GPT entry with the given 1-based number is selected by all of the partition selectors of a boot entry.
+fn is_partition_selected(entry: &BootEntry, number: usize, gpt_entry: &GPTEntry) -> bool {
+    // Copy the GUIDs out of the packed structure
+    let (partition_guid, type_guid) = (gpt_entry.partition_guid, gpt_entry.type_guid);
+    if type_guid == Guid::ZERO {
+        return false;
+    }
+
+    (entry.partition == 0 || entry.partition as usize == number) &&
+    (entry.part_uuid == Guid::ZERO || entry.part_uuid == partition_guid) &&
+    (entry.part_type == Guid::ZERO || entry.part_type == type_guid) &&
+    (entry.part_label.is_empty() || entry.part_label == gpt_entry.label())
+}
+
+/// Finds the handle of a partition on an MBR disk, addressed by the disk's signature.
+fn get_mbr_partition_handle(entry: &BootEntry) -> Result<Handle, BootFailure> {
+    // Acquire handle to disk from its signature
+    let disk_handle: Handle;
+    match DISK_SIGNATURE_HANDLE_MAPPING.read().get(&entry.disk_signature) {
+        Some(some) => {
+            disk_handle = unsafe { Handle::from_ptr(*some as *mut core::ffi::c_void).unwrap() };
+        }
+        None => {
+            return Err(BootFailure::NoDisk);
+        }
+    }
+
+    // Read the disk's primary and logical partitions
+    let partition = match CachedDiskReader::open(&disk_handle).and_then(|reader| {
+        MBR::read_mbr(&reader)?.read_partitions(&reader)
+    }) {
+        Ok(ok) => ok.into_iter().find(|t| t.number == entry.partition),
+        Err(err) => {
+            return Err(BootFailure::BadMBR(err));
+        }
+    };
+    let partition = match partition {
+        Some(some) => some,
+        None => {
+            return Err(BootFailure::NoPartition);
+        }
+    };
+
+    // Search for the partition handle - logical partitions are numbered differently by some firmware, so match on the signature and start instead
+    find_partition_handle(disk_handle, |hard_drive| {
+        hard_drive.partition_signature() == PartitionSignature::Mbr(entry.disk_signature.to_le_bytes()) &&
+        hard_drive.partition_start() == partition.starting_lba
+    })
+}
+
+/// Finds the handle of a partition of a disk whose hard drive device path node satisfies a predicate.
+///
+/// The partition's device path must extend the disk's own, so identical partitions on cloned disks are told apart.
+fn find_partition_handle(disk_handle: Handle, is_partition: impl Fn(&HardDrive) -> bool) -> Result<Handle, BootFailure> {
+    let disk_path = match dev::open_device_path(&disk_handle) {
+        Some(some) => some,
+        None => {
+            return Err(BootFailure::PartitionNotFound);
+        }
+    };
+
     for handle in uefi::boot::locate_handle_buffer(
         uefi::boot::SearchType::ByProtocol(&BlockIoProtocol::GUID)
     ).unwrap().iter() {
-        let dp_protocol: ScopedProtocol<DevicePath>;
-        unsafe {
-            match open_protocol::<DevicePath>(
-                OpenProtocolParams {
-                    handle: *handle,
-                    agent: image_handle!(),
-                    controller: None
-                },
-                OpenProtocolAttributes::GetProtocol
-            ) {
-                Ok(ok) => {
-                    dp_protocol = ok;
+        if *handle == disk_handle {
+            continue;
+        }
+        let partition_path = match dev::open_device_path(handle) {
+            Some(some) => some,
+            None => {
+                // It's possible that not every BlockIO device can open a DevicePath
+                continue;
+            }
+        };
+
+        // The partition's device path must start with the disk's...
+        let mut partition_nodes = partition_path.node_iter();
+        if !disk_path.node_iter().all(|t| partition_nodes.next().is_some_and(|node| node == t)) {
+            continue;
+        }
+
+        // ...followed only by hard drive nodes - logical partitions may be nested in the extended partition, so the last describes the partition itself
+        let mut hard_drive: Option<&HardDrive> = None;
+        for node in partition_nodes {
+            match node.as_enum() {
+                Ok(DevicePathNodeEnum::MediaHardDrive(some)) => {
+                    hard_drive = Some(some);
                 }
-                Err(_) => {
-                    // It's possible that not every BlockIO device can open a DevicePath
-                    continue;
+                _ => {
+                    hard_drive = None;
+                    break;
                 }
-            };
-
-            // Check if the device path points to the disk and partition
-            if (
-                dp_protocol.to_string(DisplayOnly(true), AllowShortcuts(false)).unwrap().to_string()
-            ).contains(&format!("HD({},GPT,{}", entry.partition, partition_guid.to_string().to_uppercase())) {
-                return Ok(*handle);
             }
         }
-    } 
+        if hard_drive.is_some_and(&is_partition) {
+            return Ok(*handle);
+        }
+    }
 
     Err(BootFailure::PartitionNotFound)
-}
\ No newline at end of file
+}
@@ -0,0 +1,111 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+
+use spin::RwLock;
+use uefi::Handle;
+use wakatiwai_udive::FSDriver;
+
+use crate::dev;
+use crate::dprintln;
+use crate::wtcore::config::BootEntry;
+
+use super::request::FSRequest;
+use super::{get_fs_driver_names, invoke_fs_driver, load_fs_driver, BootFailure};
+
+/// Volume identities read so far, keyed by file system type and partition handle address.
+///
+/// Probing a partition requires invoking a file system driver, so each partition is only probed once per file system.
+static VOLUME_ID_CACHE: RwLock<BTreeMap<(String, u64), Option<VolumeId>>> = RwLock::new(BTreeMap::new());
+
+/// The identity of a file system, as reported by its driver.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VolumeId {
+    /// The UUID (or serial number) of the volume, formatted as by `blkid`.
+    pub uuid: String,
+    /// The label of the volume, or empty if it has none.
+    pub label: String,
+}
+
+impl VolumeId {
+    /// Parses the response of a file system driver to `FSRequest::VolumeId`.
+    pub fn parse(buffer: &[u8]) -> Option<Self> {
+        let response = core::str::from_utf8(buffer).ok()?;
+        let (uuid, label) = response.split_once('\n').unwrap_or((response, ""));
+
+        Some(VolumeId {
+            uuid: uuid.trim().to_string(),
+            label: label.trim_end_matches(['\r', '\n']).to_string()
+        })
+    }
+
+    /// Checks if this volume is selected by the `fsuuid` and `fslabel` of a boot entry.
+    ///
+    /// UUIDs are compared case-insensitively, as tools differ in how they print them.
+    pub fn is_selected_by(&self, entry: &BootEntry) -> bool {
+        (entry.fs_uuid.is_empty() || entry.fs_uuid.eq_ignore_ascii_case(&self.uuid)) &&
+        (entry.fs_label.is_empty() || entry.fs_label == self.label)
+    }
+}
+
+/// Forgets all volume identities read so far, e.g. once the disks have changed.
+pub fn clear_volume_id_cache() {
+    VOLUME_ID_CACHE.write().clear();
+}
+
+/// Finds the handle of the first partition whose file system is selected by a boot entry, probing every partition with the entry's file system driver.
+pub fn get_volume_handle(entry: &BootEntry) -> Result<Handle, BootFailure> {
+    let mut fs_driver: Option<FSDriver> = None;
+
+    for handle in dev::get_block_io_device_handles() {
+        if !dev::is_logical_partition(&handle) {
+            continue;
+        }
+
+        if read_volume_id(&entry.fstype, &handle, &mut fs_driver)?.is_some_and(|t| t.is_selected_by(entry)) {
+            return Ok(handle);
+        }
+    }
+
+    Err(BootFailure::PartitionNotFound)
+}
+
+/// Determines the file system on the partition behind a handle, by asking each file system driver to identify its volume.
+pub fn identify_file_system(handle: &Handle) -> Option<String> {
+    for fstype in get_fs_driver_names() {
+        match read_volume_id(&fstype, handle, &mut None) {
+            Ok(Some(_)) => return Some(fstype),
+            Ok(None) => {}
+            Err(err) => {
+                dprintln!("Unable to load {} file system driver: {:?}", fstype, err);
+            }
+        }
+    }
+
+    None
+}
+
+/// Reads the identity of the volume on the partition behind a handle using the driver of a given file system,
+/// or `None` if the partition does not hold that file system.
+///
+/// The driver is only loaded into `fs_driver` once a partition actually has to be probed.
+fn read_volume_id(fstype: &str, handle: &Handle, fs_driver: &mut Option<FSDriver>) -> Result<Option<VolumeId>, BootFailure> {
+    let key = (fstype.to_string(), handle.as_ptr() as u64);
+    if let Some(cached) = VOLUME_ID_CACHE.read().get(&key) {
+        return Ok(cached.clone());
+    }
+
+    if fs_driver.is_none() {
+        *fs_driver = Some(load_fs_driver(fstype)?);
+    }
+    let volume_id = match invoke_fs_driver(fs_driver.as_mut().unwrap(), handle, &FSRequest::VolumeId.to_path()) {
+        Ok(ok) => VolumeId::parse(&ok),
+        Err(err) => {
+            // Most partitions will not hold this file system
+            dprintln!("Unable to identify {} volume on handle {:#010x}: {:?}", fstype, key.1, err);
+            None
+        }
+    };
+    VOLUME_ID_CACHE.write().insert(key, volume_id.clone());
+
+    Ok(volume_id)
+}
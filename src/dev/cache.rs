@@ -0,0 +1,276 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+
+use spin::RwLock;
+use uefi::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol};
+use uefi::proto::media::disk::DiskIo;
+use uefi::{Handle, Identify, Status};
+use uefi_raw::protocol::disk::DiskIoProtocol;
+use wakatiwai_udive::disk::DiskReader;
+
+use crate::image_handle;
+
+/// The number of blocks cached unless configured otherwise.
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 512;
+
+/// Blocks read through a `CachedDiskReader`, shared by all disks.
+static BLOCK_CACHE: RwLock<BlockCache> = RwLock::new(BlockCache::new());
+
+/// A least-recently-used cache of disk blocks.
+struct BlockCache {
+    /// The maximum number of blocks cached.
+    capacity: usize,
+    /// Blocks keyed by the address of their disk's handle and their LBA, least recently used first.
+    blocks: VecDeque<((u64, u64), Vec<u8>)>,
+}
+
+impl BlockCache {
+    /// Returns an empty cache of the default size.
+    const fn new() -> Self {
+        BlockCache {
+            capacity: DEFAULT_BLOCK_CACHE_SIZE,
+            blocks: VecDeque::new()
+        }
+    }
+
+    /// Returns a cached block, marking it as the most recently used.
+    fn get(&mut self, key: (u64, u64)) -> Option<&Vec<u8>> {
+        let idx = self.blocks.iter().position(|t| t.0 == key)?;
+        let block = self.blocks.remove(idx)?;
+        self.blocks.push_back(block);
+        self.blocks.back().map(|t| &t.1)
+    }
+
+    /// Caches a block, evicting the least recently used blocks if the cache is full.
+    fn insert(&mut self, key: (u64, u64), block: Vec<u8>) {
+        if let Some(idx) = self.blocks.iter().position(|t| t.0 == key) {
+            self.blocks.remove(idx);
+        }
+        if self.capacity == 0 {
+            return;
+        }
+
+        while self.blocks.len() >= self.capacity {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back((key, block));
+    }
+}
+
+/// A reader of the blocks of a disk, which reads through the shared block cache.
+///
+/// The disk is only opened to read blocks which are not cached.
+pub struct CachedDiskReader {
+    /// The handle of the disk.
+    handle: Handle,
+    /// The size of each block of the disk, in bytes.
+    pub block_size: u32,
+    /// The LBA of the last block of the disk.
+    pub last_block: u64,
+}
+
+impl CachedDiskReader {
+    /// Opens the disk behind a handle.
+    pub fn open(handle: &Handle) -> Result<Self, Status> {
+        let reader = open_disk_reader(handle)?;
+
+        Ok(CachedDiskReader {
+            handle: *handle,
+            block_size: reader.block_size,
+            last_block: reader.last_block
+        })
+    }
+
+    /// Reads a single block.
+    pub fn read_block(&self, lba: u64) -> Result<Vec<u8>, Status> {
+        self.read_blocks(lba, 1)
+    }
+
+    /// Reads a number of consecutive blocks.
+    pub fn read_blocks(&self, lba: u64, count: usize) -> Result<Vec<u8>, Status> {
+        let disk = self.handle.as_ptr() as u64;
+
+        // Serve the blocks from the cache if all of them are there
+        let mut ret = Vec::with_capacity(count * self.block_size as usize);
+        {
+            let mut cache = BLOCK_CACHE.write();
+            for block_idx in 0..count as u64 {
+                match cache.get((disk, lba + block_idx)) {
+                    Some(some) => ret.extend_from_slice(some),
+                    None => break
+                }
+            }
+        }
+        if ret.len() == count * self.block_size as usize {
+            return Ok(ret);
+        }
+
+        // Otherwise read them all at once, and cache them for next time
+        let buffer = open_disk_reader(&self.handle)?.read_blocks(lba, count)?;
+        let mut cache = BLOCK_CACHE.write();
+        // Reads larger than the cache would only evict everything else
+        if count > cache.capacity {
+            return Ok(buffer);
+        }
+        for (block_idx, block) in buffer.chunks(self.block_size as usize).enumerate() {
+            cache.insert((disk, lba + block_idx as u64), block.to_vec());
+        }
+
+        Ok(buffer)
+    }
+}
+
+/// A `DiskIo` protocol reading a partition through the block cache, followed by the reader it reads through.
+#[repr(C)]
+struct CachedDiskIo {
+    protocol: DiskIoProtocol,
+    reader: CachedDiskReader,
+}
+
+/// A `DiskIo` protocol which reads a partition through the block cache, so that file system drivers share the cache.
+/// The protocol is installed on a handle of its own, and stays available until dropped.
+pub struct CachedDiskIoDevice {
+    handle: Handle,
+    disk_io: Box<CachedDiskIo>,
+}
+
+impl CachedDiskIoDevice {
+    /// Installs a `DiskIo` protocol reading the partition behind a handle through the block cache.
+    pub fn install(partition_handle: &Handle) -> Result<Self, Status> {
+        let disk_io = Box::new(CachedDiskIo {
+            protocol: DiskIoProtocol {
+                revision: DiskIoProtocol::REVISION,
+                read_disk: read_cached_disk,
+                write_disk: write_cached_disk
+            },
+            reader: CachedDiskReader::open(partition_handle)?
+        });
+
+        let handle = match unsafe {
+            uefi::boot::install_protocol_interface(
+                None,
+                &DiskIo::GUID,
+                &disk_io.protocol as *const DiskIoProtocol as *const c_void
+            )
+        } {
+            Ok(ok) => ok,
+            Err(err) => return Err(err.status())
+        };
+
+        Ok(CachedDiskIoDevice { handle, disk_io })
+    }
+
+    /// Opens the installed `DiskIo` protocol.
+    pub fn open(&self) -> Result<ScopedProtocol<DiskIo>, Status> {
+        unsafe {
+            match uefi::boot::open_protocol::<DiskIo>(
+                OpenProtocolParams {
+                    handle: self.handle,
+                    agent: image_handle!(),
+                    controller: None
+                },
+                OpenProtocolAttributes::GetProtocol
+            ) {
+                Ok(ok) => Ok(ok),
+                Err(err) => Err(err.status())
+            }
+        }
+    }
+}
+
+impl Drop for CachedDiskIoDevice {
+    fn drop(&mut self) {
+        // Nothing else uses the handle, so a failure can be ignored
+        unsafe {
+            let _ = uefi::boot::uninstall_protocol_interface(
+                self.handle,
+                &DiskIo::GUID,
+                &self.disk_io.protocol as *const DiskIoProtocol as *const c_void
+            );
+        }
+    }
+}
+
+/// Reads bytes of a partition through the block cache.
+unsafe extern "efiapi" fn read_cached_disk(
+    this: *const DiskIoProtocol,
+    _media_id: u32,
+    offset: u64,
+    buffer_size: usize,
+    buffer: *mut c_void
+) -> Status {
+    if this.is_null() || (buffer.is_null() && buffer_size != 0) {
+        return Status::INVALID_PARAMETER;
+    }
+    if buffer_size == 0 {
+        return Status::SUCCESS;
+    }
+
+    // The protocol is the first field of the `CachedDiskIo`, so the reader can be recovered from it
+    let reader = &(*(this as *const CachedDiskIo)).reader;
+    let block_size = reader.block_size as u64;
+    let first_block = offset / block_size;
+    let last_block = match offset.checked_add(buffer_size as u64 - 1) {
+        Some(some) => some / block_size,
+        None => return Status::INVALID_PARAMETER
+    };
+    if last_block > reader.last_block {
+        return Status::INVALID_PARAMETER;
+    }
+
+    match reader.read_blocks(first_block, (last_block - first_block + 1) as usize) {
+        Ok(ok) => {
+            let start = (offset % block_size) as usize;
+            core::ptr::copy_nonoverlapping(ok[start..].as_ptr(), buffer as *mut u8, buffer_size);
+            Status::SUCCESS
+        }
+        Err(err) => err
+    }
+}
+
+/// Refuses to write, as file system drivers only read.
+unsafe extern "efiapi" fn write_cached_disk(
+    _this: *mut DiskIoProtocol,
+    _media_id: u32,
+    _offset: u64,
+    _buffer_size: usize,
+    _buffer: *const c_void
+) -> Status {
+    Status::WRITE_PROTECTED
+}
+
+/// Sets the maximum number of blocks cached, where 0 disables the cache.
+pub fn set_block_cache_size(blocks: usize) {
+    let mut cache = BLOCK_CACHE.write();
+    cache.capacity = blocks;
+    while cache.blocks.len() > blocks {
+        cache.blocks.pop_front();
+    }
+}
+
+/// Forgets all cached blocks, e.g. once the disks have changed.
+pub fn clear_block_cache() {
+    BLOCK_CACHE.write().blocks.clear();
+}
+
+/// Opens a reader of the disk behind a handle, bypassing the block cache.
+fn open_disk_reader(handle: &Handle) -> Result<DiskReader, Status> {
+    unsafe {
+        // Cannot open as exclusive otherwise crashes
+        match uefi::boot::open_protocol::<DiskIo>(
+            OpenProtocolParams {
+                handle: *handle,
+                agent: image_handle!(),
+                controller: None
+            },
+            OpenProtocolAttributes::GetProtocol
+        ) {
+            Ok(ok) => Ok(DiskReader::new(handle, ok, 0)),
+            Err(err) => Err(err.status())
+        }
+    }
+}
@@ -1,34 +1,47 @@
 extern crate alloc;
 
+use alloc::string::String;
 use alloc::vec::Vec;
 use core::borrow::Borrow;
 use core::mem::size_of;
 
 use crc::*;
 use uefi::{Char16, Guid, Status};
-use wakatiwai_udive::disk::DiskReader;
 
 use crate::dprintln;
+use super::cache::CachedDiskReader;
 use super::mbr::MBR;
 
+/// The CRC32 variant used by the GPT headers and entry arrays.
+const CRC32_HASHER: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
 /// A structure describing the GUID Partition Table (GPT).
 #[derive(Debug)]
 pub struct GPT {
     /// Protective MBR.
     pub pmbr: MBR,
-    /// Primary header.
+    /// Header of the copy in use.
     pub header: GPTHeader,
-    /// Primary GPTEntry array.
+    /// GPTEntry array of the copy in use.
     pub entries: Vec<GPTEntry>,
-    /// Alternate GPTEntry array.
-    pub alt_entries: Vec<GPTEntry>,
-    /// Alternate header.
-    pub alt_header: GPTHeader
+    /// Which copies of the GPT are intact, and so which is in use.
+    pub source: GPTSource
+}
+
+/// Describes which copies of a GPT were found intact.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GPTSource {
+    /// Both copies are intact and identical, the primary copy is used.
+    Both,
+    /// The alternate copy is damaged or differs, the primary copy is used.
+    Primary,
+    /// The primary copy is damaged, the alternate copy is used.
+    Alternate
 }
 
 impl GPT {
-    /// Reads the GPT from a disk.
-    pub fn read_gpt(reader: &DiskReader) -> Result<Self, Status> {
+    /// Reads the GPT from a disk, falling back to whichever of its copies is intact if the other is damaged.
+    pub fn read_gpt(reader: &CachedDiskReader) -> Result<Self, Status> {
         // Read the PMBR
         let pmbr: MBR;
         match MBR::read_mbr(reader) {
@@ -39,63 +52,44 @@ impl GPT {
                 return Err(err);
             }
         }
-    
-        // Read the headers
-        let (header, alt_header): (GPTHeader, GPTHeader);
-        match reader.read_block(1) {
-            Ok(ok) => unsafe {
-                header = *(ok[0..size_of::<GPTHeader>()].as_ptr() as *const GPTHeader);
 
-                if !header.is_valid() {
-                    dprintln!("Primary Header is invalid");
-                    return Err(Status::ABORTED);
+        // Read both copies, either of which may be damaged
+        let primary = GPT::read_copy(reader, 1);
+        let alternate = GPT::read_copy(reader, reader.last_block);
+        let (header, entries, source): (GPTHeader, Vec<GPTEntry>, GPTSource);
+        match (primary, alternate) {
+            (Ok((primary_header, primary_entries)), Ok((_, alt_entries))) => {
+                source = if primary_entries == alt_entries {
+                    GPTSource::Both
                 }
-            },
-            Err(err) => {
-                return Err(err);
+                else {
+                    dprintln!("Alternate GPT entry array differs from the primary");
+                    GPTSource::Primary
+                };
+                header = primary_header;
+                entries = primary_entries;
             }
-        }
-        match reader.read_block(reader.last_block){
-            Ok(ok) => unsafe {
-                alt_header = *(ok[0..size_of::<GPTHeader>()].as_ptr() as *const GPTHeader);
-
-                if !alt_header.is_valid() {
-                    dprintln!("Alternate Header is invalid");
-                    return Err(Status::ABORTED);
-                }
-            },
-            Err(err) => {
-                return Err(err);
+            (Ok((primary_header, primary_entries)), Err(_)) => {
+                source = GPTSource::Primary;
+                header = primary_header;
+                entries = primary_entries;
             }
-        }
-
-        // Read the entries
-        let (entries, alt_entries): (Vec<GPTEntry>, Vec<GPTEntry>);
-        match header.get_entries(reader) {
-            Ok(ok) => {
-                entries = ok;
+            (Err(_), Ok((alt_header, alt_entries))) => {
+                source = GPTSource::Alternate;
+                header = alt_header;
+                entries = alt_entries;
             }
-            Err(err) => {
-                dprintln!("Failed to read entries from primary GPT header");
+            (Err(err), Err(_)) => {
+                dprintln!("Neither GPT copy is intact");
                 return Err(err);
             }
         }
-        match alt_header.get_entries(reader) {
-            Ok(ok) => {
-                alt_entries = ok;
-            }
-            Err(err) => {
-                dprintln!("Failed to read entries from alternate GPT header");
-                return Err(err);
-            }
-        }
-    
+
         let ret = GPT {
             pmbr,
             header,
             entries,
-            alt_entries,
-            alt_header
+            source
         };
 
         // Check for validity
@@ -107,19 +101,38 @@ impl GPT {
         Ok(ret)
     }
 
+    /// Reads the GPT header at a given LBA and the entry array it describes, checking both are intact.
+    fn read_copy(reader: &CachedDiskReader, lba: u64) -> Result<(GPTHeader, Vec<GPTEntry>), Status> {
+        let header: GPTHeader;
+        match reader.read_block(lba) {
+            Ok(ok) => unsafe {
+                header = *(ok[0..size_of::<GPTHeader>()].as_ptr() as *const GPTHeader);
+
+                if !header.is_valid() {
+                    dprintln!("GPT header at LBA {} is invalid", lba);
+                    return Err(Status::ABORTED);
+                }
+            },
+            Err(err) => {
+                return Err(err);
+            }
+        }
+
+        match header.get_entries(reader) {
+            Ok(ok) => Ok((header, ok)),
+            Err(err) => {
+                dprintln!("Failed to read entries from GPT header at LBA {}", lba);
+                Err(err)
+            }
+        }
+    }
+
     /// Checks if the GPT is valid.
     pub fn is_valid(&self) -> bool {
         // TODO: the MBR can also be non-protective and describe a uefi partition (partition type 0xEF), implement this?
 
         self.is_mbr_protective() &&         // Is the MBR protective? (validity checked in reading)
-        self.header.is_valid() &&           // Is the primary header valid?
-        self.alt_header.is_valid() &&       // Is the alternate header valid?
-        self.entries.iter()                 // Are the primary and alternate entry arrays valid?
-            .zip(self.alt_entries.iter())
-            .filter(
-                |(entry,alt_entry)|
-                entry != alt_entry
-            ).count() == 0
+        self.header.is_valid()              // Is the header in use valid?
     }
 
     /// Checks if the PMBR is GPT protective compliant.
@@ -224,9 +237,8 @@ impl GPTHeader {
             let mut payload = self.clone();
             payload.header_crc32 = 0;
             let payload_array = core::slice::from_raw_parts(payload.borrow() as *const GPTHeader as *const u8, self.header_size as usize);
-            const HASHER: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
-            if HASHER.checksum(payload_array) != self.header_crc32 {
+            if CRC32_HASHER.checksum(payload_array) != self.header_crc32 {
                 dprintln!("Bad Header CRC32 Checksum");
                 return false;
             }
@@ -235,15 +247,16 @@ impl GPTHeader {
         true
     }
 
-    /// Obtains all of the entries pointed to by a GPT Header.
-    fn get_entries(&self, reader: &DiskReader) -> Result<Vec<GPTEntry>, Status> {
+    /// Obtains all of the entries pointed to by a GPT Header, checking the entry array is intact.
+    fn get_entries(&self, reader: &CachedDiskReader) -> Result<Vec<GPTEntry>, Status> {
         let mut ret = Vec::<GPTEntry>::new();
 
         // Read all the blocks that contain the GPT entries
+        let partition_entry_array_size = self.entry_size as u64 * self.entry_count as u64;
         let partition_entry_array_bytes: Vec<u8>;
         match reader.read_blocks(
             self.entry_array_starting_lba,
-            ((self.entry_size * self.entry_count) / reader.block_size & !reader.block_size) as usize
+            partition_entry_array_size.div_ceil(reader.block_size as u64) as usize
         ) {
             Ok(ok) => {
                 partition_entry_array_bytes = ok;
@@ -253,6 +266,14 @@ impl GPTHeader {
             }
         }
 
+        match partition_entry_array_bytes.get(0..partition_entry_array_size as usize) {
+            Some(some) if CRC32_HASHER.checksum(some) == self.entry_array_crc32 => {}
+            _ => {
+                dprintln!("Bad Entry Array CRC32 Checksum");
+                return Err(Status::CRC_ERROR);
+            }
+        }
+
         // Dereference entries from the above byte buffer, push to a return array
         for entry_idx in 0..self.entry_count {
             let entry = partition_entry_array_bytes.get((entry_idx * self.entry_size) as usize).unwrap() as *const u8 as *const GPTEntry;
@@ -280,4 +301,15 @@ pub struct GPTEntry {
     pub attributes: u64,
     /// Null-terminated string containing the human-readable name of this partition.
     pub name: [Char16; 36]
-}
\ No newline at end of file
+}
+
+impl GPTEntry {
+    /// Returns the human-readable name of the partition.
+    pub fn label(&self) -> String {
+        // Copy the name out of the packed structure before iterating over it
+        let name = self.name;
+        char::decode_utf16(name.iter().map(|t| u16::from(*t)).take_while(|t| *t != 0))
+            .map(|t| t.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+}
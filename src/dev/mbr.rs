@@ -1,9 +1,12 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
 use core::mem::size_of;
 
 use uefi::Status;
-use wakatiwai_udive::disk::DiskReader;
 
 use crate::dprintln;
+use super::cache::CachedDiskReader;
 
 /// A structure describing the Master Boot Record (MBR).
 #[derive(Clone, Copy, Debug,)]
@@ -26,7 +29,7 @@ impl MBR {
     const MBR_SIGNATURE: u16 = 0xAA55;
 
     /// Reads the MBR from a disk.
-    pub fn read_mbr(reader: &DiskReader) -> Result<Self, Status> {
+    pub fn read_mbr(reader: &CachedDiskReader) -> Result<Self, Status> {
         let ret: MBR;
         match reader.read_block(0) {
             Ok(ok) => unsafe {
@@ -72,4 +75,105 @@ pub struct MBRPartitionEntry {
     pub starting_lba: u32,
     /// Number of sectors in the partition.
     pub sectors: u32
-}
\ No newline at end of file
+}
+
+impl MBR {
+    /// The partition types of extended partitions, which contain a chain of logical partitions.
+    const EXTENDED_PARTITION_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+    /// The partition type of a GPT protective MBR partition.
+    const PROTECTIVE_PARTITION_TYPE: u8 = 0xEE;
+    /// The maximum number of EBRs followed in the chain of an extended partition, guarding against cyclic EBR chains.
+    const MAX_EBRS: usize = 128;
+
+    /// Checks if the MBR only protects a GPT, rather than describing partitions itself.
+    pub fn is_protective(&self) -> bool {
+        self.entries.iter().any(|t| t.partition_type == Self::PROTECTIVE_PARTITION_TYPE)
+    }
+
+    /// Reads the partitions described by the MBR, numbered as Linux numbers them:
+    /// primary partitions are numbered by their slot (1 to 4), and logical partitions from 5 in the order of the EBR chains.
+    pub fn read_partitions(&self, reader: &CachedDiskReader) -> Result<Vec<MBRPartition>, Status> {
+        let mut ret = Vec::new();
+        // Logical partitions are numbered consecutively, even across several extended partitions
+        let mut next_logical_number = 5;
+
+        for (idx, entry) in self.entries.iter().enumerate() {
+            if entry.partition_type == 0 {
+                continue;
+            }
+            if Self::EXTENDED_PARTITION_TYPES.contains(&entry.partition_type) {
+                let mut logical = Self::read_logical_partitions(reader, entry.starting_lba as u64, next_logical_number)?;
+                next_logical_number = next_logical_number.saturating_add(logical.len() as u8);
+                ret.append(&mut logical);
+                continue;
+            }
+
+            ret.push(MBRPartition::from_entry(entry, (idx + 1) as u8, 0));
+        }
+
+        ret.sort_by_key(|t| t.number);
+        Ok(ret)
+    }
+
+    /// Follows the EBR chain of an extended partition starting at the given LBA, numbering its logical partitions from the given number.
+    fn read_logical_partitions(reader: &CachedDiskReader, extended_lba: u64, first_number: u8) -> Result<Vec<MBRPartition>, Status> {
+        let mut ret = Vec::new();
+        let mut ebr_lba = extended_lba;
+
+        for _ in 0..Self::MAX_EBRS {
+            let ebr = match reader.read_block(ebr_lba) {
+                Ok(ok) => unsafe { *(ok[0..size_of::<MBR>()].as_ptr() as *const MBR) },
+                Err(err) => return Err(err)
+            };
+            if !ebr.is_valid() {
+                dprintln!("EBR at LBA {} is invalid", ebr_lba);
+                break;
+            }
+
+            // The first entry describes the logical partition relative to its EBR
+            let logical = ebr.entries[0];
+            if logical.partition_type != 0 {
+                // Partition numbers cannot go past 255
+                let number = match first_number.checked_add(ret.len() as u8) {
+                    Some(some) => some,
+                    None => break
+                };
+                ret.push(MBRPartition::from_entry(&logical, number, ebr_lba));
+            }
+
+            // The second entry points to the next EBR, relative to the start of the extended partition
+            let next = ebr.entries[1];
+            if next.partition_type == 0 || next.starting_lba == 0 {
+                break;
+            }
+            ebr_lba = extended_lba + next.starting_lba as u64;
+        }
+
+        Ok(ret)
+    }
+}
+
+/// A partition described by an MBR, with its absolute location on the disk.
+#[derive(Clone, Copy, Debug)]
+pub struct MBRPartition {
+    /// The (1-based) number of the partition.
+    pub number: u8,
+    /// The partition type.
+    pub partition_type: u8,
+    /// LBA address of the first absolute sector in the partition.
+    pub starting_lba: u64,
+    /// Number of sectors in the partition.
+    pub sectors: u64
+}
+
+impl MBRPartition {
+    /// Converts a partition entry whose LBA is relative to the given LBA.
+    fn from_entry(entry: &MBRPartitionEntry, number: u8, relative_to: u64) -> Self {
+        MBRPartition {
+            number,
+            partition_type: entry.partition_type,
+            starting_lba: relative_to + entry.starting_lba as u64,
+            sectors: entry.sectors as u64
+        }
+    }
+}
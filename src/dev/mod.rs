@@ -1,22 +1,95 @@
+pub mod cache;
 pub mod mbr;
 pub mod gpt;
+pub mod smbios;
 
 extern crate alloc;
 
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use spin::Lazy;
-use uefi::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol, SearchType};
-use uefi::proto::media::disk::DiskIo;
-use uefi::{Guid, Handle};
+use spin::{Lazy, RwLock};
+use uefi::boot::{EventType, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol, SearchType, Tpl};
+use uefi::proto::device_path::media::PartitionSignature;
+use uefi::proto::device_path::{DevicePath, DevicePathNodeEnum};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::block::BlockIO;
+use uefi::{Event, Guid, Handle, Status};
 use uefi_raw::protocol::*;
-use wakatiwai_udive::disk::DiskReader;
 
-use crate::{dprintln, eprintln, image_handle};
+use crate::{dprintln, eprintln, image_handle, wprintln};
+use cache::CachedDiskReader;
 
-/// A map of GPT disk GUIDs to the corresponding disk handle addresses.
-pub static DISK_GUID_HANDLE_MAPPING: Lazy<BTreeMap<Guid, u64>> = Lazy::new(|| {
+/// A map of GPT disk GUIDs to the corresponding disk handle addresses, updated by `rescan_disks`.
+pub static DISK_GUID_HANDLE_MAPPING: Lazy<RwLock<BTreeMap<Guid, u64>>> = Lazy::new(|| {
+    RwLock::new(scan_disk_guids())
+});
+
+/// A map of MBR disk signatures to the corresponding disk handle addresses for disks without a GPT, updated by `rescan_disks`.
+pub static DISK_SIGNATURE_HANDLE_MAPPING: Lazy<RwLock<BTreeMap<u32, u64>>> = Lazy::new(|| {
+    RwLock::new(scan_disk_signatures(&DISK_GUID_HANDLE_MAPPING.read()))
+});
+
+/// Determines if either copy of the GPT of any disk was found to be damaged or outdated while scanning disks.
+pub static DAMAGED_GPT: AtomicBool = AtomicBool::new(false);
+
+/// Address of an event signalled whenever a BlockIO protocol is installed, i.e. whenever a disk or partition appears.
+static BLOCK_IO_INSTALL_EVENT: Lazy<Option<u64>> = Lazy::new(|| {
+    unsafe {
+        let event = match uefi::boot::create_event(EventType::empty(), Tpl::CALLBACK, None, None) {
+            Ok(ok) => ok,
+            Err(err) => {
+                dprintln!("Unable to create BlockIO install event: {:?}", err.status());
+                return None;
+            }
+        };
+        if let Err(err) = uefi::boot::register_protocol_notify(&block::BlockIoProtocol::GUID, &event) {
+            dprintln!("Unable to register for BlockIO installs: {:?}", err.status());
+            let _ = uefi::boot::close_event(event);
+            return None;
+        }
+
+        Some(event.as_ptr() as u64)
+    }
+});
+
+/// Rescans all disks, e.g. once removable media has been inserted or removed.
+///
+/// Returns `true` if any disk has appeared, disappeared or changed.
+pub fn rescan_disks() -> bool {
+    // Make sure the firmware has bound its partition and file system drivers to any new media
+    for device_handle in get_block_io_device_handles() {
+        let _ = uefi::boot::connect_controller(device_handle, None, None, true);
+    }
+    // Cached blocks may belong to media which have since been swapped
+    cache::clear_block_cache();
+
+    let disk_guid_handle_mapping = scan_disk_guids();
+    let disk_signature_handle_mapping = scan_disk_signatures(&disk_guid_handle_mapping);
+    let changed =
+        *DISK_GUID_HANDLE_MAPPING.read() != disk_guid_handle_mapping ||
+        *DISK_SIGNATURE_HANDLE_MAPPING.read() != disk_signature_handle_mapping;
+
+    *DISK_GUID_HANDLE_MAPPING.write() = disk_guid_handle_mapping;
+    *DISK_SIGNATURE_HANDLE_MAPPING.write() = disk_signature_handle_mapping;
+    changed
+}
+
+/// Checks if a disk or partition has appeared since this was last checked.
+///
+/// Removals are not signalled by the firmware, so they are only noticed by rescanning.
+pub fn block_io_installed() -> bool {
+    let event = match *BLOCK_IO_INSTALL_EVENT {
+        Some(some) => unsafe { Event::from_ptr(some as *mut core::ffi::c_void) },
+        None => None
+    };
+
+    event.is_some_and(|t| uefi::boot::check_event(t).unwrap_or(false))
+}
+
+/// Maps the GUIDs of all GPT disks to their handle addresses.
+fn scan_disk_guids() -> BTreeMap<Guid, u64> {
     let mut disk_guid_handle_mapping: BTreeMap<Guid, u64> = BTreeMap::new();
     
     // Iterate all devide handles
@@ -25,56 +98,86 @@ pub static DISK_GUID_HANDLE_MAPPING: Lazy<BTreeMap<Guid, u64>> = Lazy::new(|| {
         dprintln!("Opening device handle {:#010x}", device_handle.as_ptr() as u64);
         
         // Attempt to open a DiskIo protocol on this device, continue if unable to do so
-        let protocol: ScopedProtocol<DiskIo>;
-        unsafe {
-            // Cannot open as exclusive otherwise crashes
-            match uefi::boot::open_protocol::<DiskIo>(
-                OpenProtocolParams {
-                    handle: *device_handle,
-                    agent: image_handle!(),
-                    controller: None
-                },
-                OpenProtocolAttributes::GetProtocol
-            ) {
-                Ok(ok) => {
-                    protocol = ok;
-                }
-                Err(err) => {
-                    dprintln!("Unable to open DiskIO protocol on device handle {:#010x}: {:?}", device_handle.as_ptr() as u64, err.status());
-                    continue;
-                }
+        let reader = match CachedDiskReader::open(device_handle) {
+            Ok(ok) => ok,
+            Err(err) => {
+                dprintln!("Unable to open DiskIO protocol on device handle {:#010x}: {:?}", device_handle.as_ptr() as u64, err);
+                continue;
             }
-        }
-
-        let reader = DiskReader::new(device_handle, protocol, 0);
+        };
 
         // Attempt to read a GPT, push its GUID and the current handle if it exists, otherwise continue
         match gpt::GPT::read_gpt(&reader) {
             Ok(ok) => {
+                // Copy the GUID out of the packed structure
+                let disk_guid = ok.header.disk_guid;
+                match ok.source {
+                    gpt::GPTSource::Both => {}
+                    gpt::GPTSource::Primary => {
+                        wprintln!("Alternate GPT of disk {} is damaged or outdated, using the primary GPT", disk_guid);
+                        DAMAGED_GPT.store(true, Ordering::Relaxed);
+                    }
+                    gpt::GPTSource::Alternate => {
+                        wprintln!("Primary GPT of disk {} is damaged, using the alternate GPT", disk_guid);
+                        DAMAGED_GPT.store(true, Ordering::Relaxed);
+                    }
+                }
+
                 disk_guid_handle_mapping.insert(
-                    ok.header.disk_guid,
+                    disk_guid,
                     device_handle.as_ptr() as u64
                 );
             }
             Err(err) => {
-                dprintln!("Unable to read current disk GPT on media {}: {:?}", reader.media_id, err);
+                dprintln!("Unable to read current disk GPT on device handle {:#010x}: {:?}", device_handle.as_ptr() as u64, err);
             }
         }
     }
 
     disk_guid_handle_mapping
-});
+}
+
+/// Maps the signatures of all MBR disks to their handle addresses, skipping the given GPT disks.
+fn scan_disk_signatures(disk_guid_handle_mapping: &BTreeMap<Guid, u64>) -> BTreeMap<u32, u64> {
+    let mut disk_signature_handle_mapping: BTreeMap<u32, u64> = BTreeMap::new();
+
+    for device_handle in get_block_io_device_handles().iter() {
+        // Partitions start with a boot sector carrying the same signature as an MBR, so only whole disks are checked
+        if is_logical_partition(device_handle) {
+            continue;
+        }
+        // Disks with a GPT are addressed by their GUID instead
+        if disk_guid_handle_mapping.values().any(|t| *t == device_handle.as_ptr() as u64) {
+            continue;
+        }
+
+        match read_handle_mbr(device_handle) {
+            Ok(ok) if !ok.is_protective() && ok.disk_signature != 0 => {
+                disk_signature_handle_mapping.insert(
+                    ok.disk_signature,
+                    device_handle.as_ptr() as u64
+                );
+            }
+            Ok(_) => {}
+            Err(err) => {
+                dprintln!("Unable to read MBR on device handle {:#010x}: {:?}", device_handle.as_ptr() as u64, err);
+            }
+        }
+    }
 
-/// The GPT GUID of the bootloader.
+    disk_signature_handle_mapping
+}
+
+/// The GPT GUID of the disk containing the bootloader.
 pub static BOOTLOADER_DISK_GUID: Lazy<Guid> = Lazy::new(|| {
-    // First handle is always the current media (I think?)
-    let device_handles = get_block_io_device_handles();
-    let bootloader_device_handle = device_handles.get(0).unwrap();
-    
-    // Iterate over all disk GUIDS to find a matching handle
-    for mapping in DISK_GUID_HANDLE_MAPPING.iter() {
-        if *mapping.1 == bootloader_device_handle.as_ptr() as u64 {
-            return *mapping.0;
+    // Walk up from the device the bootloader was loaded from to its disk
+    let disk_handle = get_bootloader_device_handle().and_then(|t| get_parent_disk_handle(&t));
+    if let Some(disk_handle) = disk_handle {
+        // Iterate over all disk GUIDS to find a matching handle
+        for mapping in DISK_GUID_HANDLE_MAPPING.read().iter() {
+            if *mapping.1 == disk_handle.as_ptr() as u64 {
+                return *mapping.0;
+            }
         }
     }
 
@@ -83,6 +186,86 @@ pub static BOOTLOADER_DISK_GUID: Lazy<Guid> = Lazy::new(|| {
     Guid::ZERO
 });
 
+/// The GPT GUID of the partition containing the bootloader.
+pub static BOOTLOADER_PARTITION_GUID: Lazy<Guid> = Lazy::new(|| {
+    let device_path = match get_bootloader_device_handle().and_then(|t| open_device_path(&t)) {
+        Some(some) => some,
+        None => {
+            eprintln!("Cannot ascertain bootloader partition GUID");
+            return Guid::ZERO;
+        }
+    };
+
+    // The last hard drive node describes the partition itself
+    let partition_guid = device_path.node_iter().filter_map(|node| {
+        match node.as_enum() {
+            Ok(DevicePathNodeEnum::MediaHardDrive(hard_drive)) => Some(hard_drive.partition_signature()),
+            _ => None
+        }
+    }).last();
+    match partition_guid {
+        Some(PartitionSignature::Guid(guid)) => guid,
+        _ => {
+            eprintln!("Cannot ascertain bootloader partition GUID");
+            Guid::ZERO
+        }
+    }
+});
+
+/// Returns the handle of the device (i.e. partition) the bootloader was loaded from.
+fn get_bootloader_device_handle() -> Option<Handle> {
+    unsafe {
+        uefi::boot::open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle: image_handle!(),
+                agent: image_handle!(),
+                controller: None
+            },
+            OpenProtocolAttributes::GetProtocol
+        ).ok()?.device()
+    }
+}
+
+/// Returns the handle of the whole disk a partition handle resides on.
+///
+/// The disk's device path is the partition's without its hard drive nodes.
+fn get_parent_disk_handle(handle: &Handle) -> Option<Handle> {
+    let partition_path = open_device_path(handle)?;
+    let disk_nodes: Vec<_> = partition_path.node_iter().take_while(|node| {
+        !matches!(node.as_enum(), Ok(DevicePathNodeEnum::MediaHardDrive(_)))
+    }).collect();
+
+    for device_handle in get_block_io_device_handles().iter() {
+        if is_logical_partition(device_handle) {
+            continue;
+        }
+        let disk_path = match open_device_path(device_handle) {
+            Some(some) => some,
+            None => continue
+        };
+
+        if disk_path.node_iter().eq(disk_nodes.iter().copied()) {
+            return Some(*device_handle);
+        }
+    }
+
+    None
+}
+
+/// Opens the device path of a handle.
+pub fn open_device_path(handle: &Handle) -> Option<ScopedProtocol<DevicePath>> {
+    unsafe {
+        uefi::boot::open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle: *handle,
+                agent: image_handle!(),
+                controller: None
+            },
+            OpenProtocolAttributes::GetProtocol
+        ).ok()
+    }
+}
+
 /// Returns an array of all BlockIO Handles.
 pub fn get_block_io_device_handles() -> Vec<Handle> {
     let mut ret = Vec::new();
@@ -96,4 +279,69 @@ pub fn get_block_io_device_handles() -> Vec<Handle> {
     }
 
     return ret;
+}
+
+/// Reads the GPT of the disk behind a given handle.
+pub fn read_handle_gpt(handle: &Handle) -> Result<gpt::GPT, Status> {
+    gpt::GPT::read_gpt(&CachedDiskReader::open(handle)?)
+}
+
+/// Reads the MBR of the disk behind a given handle.
+pub fn read_handle_mbr(handle: &Handle) -> Result<mbr::MBR, Status> {
+    mbr::MBR::read_mbr(&CachedDiskReader::open(handle)?)
+}
+
+/// Checks if a BlockIO handle is a partition, rather than a whole disk.
+pub fn is_logical_partition(handle: &Handle) -> bool {
+    unsafe {
+        match uefi::boot::open_protocol::<BlockIO>(
+            OpenProtocolParams {
+                handle: *handle,
+                agent: image_handle!(),
+                controller: None
+            },
+            OpenProtocolAttributes::GetProtocol
+        ) {
+            Ok(ok) => ok.media().is_logical_partition(),
+            // Assume the worst
+            Err(_) => true
+        }
+    }
+}
+
+/// Finds the disk GUID and (1-based) partition number of the GPT partition with the given partition GUID.
+pub fn locate_partition(partition_guid: Guid) -> Option<(Guid, u8)> {
+    for (disk_guid, disk_handle_addr) in DISK_GUID_HANDLE_MAPPING.read().iter() {
+        let disk_handle = unsafe { Handle::from_ptr(*disk_handle_addr as *mut core::ffi::c_void).unwrap() };
+        let disk_gpt = match read_handle_gpt(&disk_handle) {
+            Ok(ok) => ok,
+            Err(_) => continue
+        };
+
+        if let Some(idx) = disk_gpt.entries.iter().position(|t| t.partition_guid == partition_guid) {
+            return Some((*disk_guid, (idx + 1) as u8));
+        }
+    }
+
+    None
+}
+
+/// Finds the disk GUID and (1-based) partition number of the GPT partition a handle resides on.
+pub fn locate_handle_partition(handle: &Handle) -> Option<(Guid, u8)> {
+    let device_path = open_device_path(handle)?;
+
+    // The hard drive node carries the partition GUID as its signature
+    let partition_guid = device_path.node_iter().find_map(|node| {
+        match node.as_enum() {
+            Ok(DevicePathNodeEnum::MediaHardDrive(hard_drive)) => {
+                match hard_drive.partition_signature() {
+                    PartitionSignature::Guid(guid) => Some(guid),
+                    _ => None
+                }
+            }
+            _ => None
+        }
+    })?;
+
+    locate_partition(partition_guid)
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::Lazy;
+use uefi::{guid, Guid};
+
+use crate::dprintln;
+
+/// GUID of the SMBIOS 3.0 entry point configuration table.
+const SMBIOS3_TABLE_GUID: Guid = guid!("f2fd1544-9794-4a2c-992e-e5bbcf20e394");
+/// GUID of the SMBIOS 2.x entry point configuration table.
+const SMBIOS_TABLE_GUID: Guid = guid!("eb9d2d31-2d88-11d3-9a16-0090273fc14d");
+
+/// The SMBIOS structure type describing the system.
+const SYSTEM_INFORMATION_TYPE: u8 = 1;
+/// The SMBIOS structure type marking the end of the structure table.
+const END_OF_TABLE_TYPE: u8 = 127;
+
+/// The system information reported by the firmware's SMBIOS tables.
+pub static SYSTEM_INFORMATION: Lazy<SystemInformation> = Lazy::new(|| {
+    match SystemInformation::read() {
+        Some(some) => some,
+        None => {
+            dprintln!("Unable to read SMBIOS system information");
+            SystemInformation::default()
+        }
+    }
+});
+
+/// A structure describing the SMBIOS System Information (Type 1) structure.
+/// [Specification](https://www.dmtf.org/sites/default/files/standards/documents/DSP0134_3.7.0.pdf).
+#[derive(Debug, Default)]
+pub struct SystemInformation {
+    /// The manufacturer of the system.
+    pub manufacturer: String,
+    /// The product name of the system.
+    pub product: String,
+    /// The version of the system.
+    pub version: String,
+}
+
+impl SystemInformation {
+    /// Reads the system information from the SMBIOS structure table.
+    fn read() -> Option<Self> {
+        let table = read_structure_table()?;
+
+        // Walk the structures until the system information is found
+        let mut offset = 0;
+        while offset + 4 <= table.len() {
+            let structure_type = table[offset];
+            let structure_length = table[offset + 1] as usize;
+            if structure_type == END_OF_TABLE_TYPE || structure_length < 4 {
+                break;
+            }
+
+            // The formatted area is followed by a set of strings, terminated by two NULs
+            let strings_start = offset + structure_length;
+            let mut strings_end = strings_start;
+            while strings_end + 1 < table.len() && !(table[strings_end] == 0 && table[strings_end + 1] == 0) {
+                strings_end += 1;
+            }
+            let formatted = table.get(offset..strings_start)?;
+            let strings: Vec<&[u8]> = table.get(strings_start..strings_end)?.split(|t| *t == 0).collect();
+
+            if structure_type == SYSTEM_INFORMATION_TYPE {
+                // Strings are referenced by their 1-based index, 0 meaning none
+                let get_string = |index: Option<&u8>| -> String {
+                    match index {
+                        Some(index) if *index > 0 => {
+                            strings.get(*index as usize - 1)
+                                .map(|t| String::from_utf8_lossy(t).trim().into())
+                                .unwrap_or_default()
+                        }
+                        _ => String::new()
+                    }
+                };
+
+                return Some(SystemInformation {
+                    manufacturer: get_string(formatted.get(4)),
+                    product: get_string(formatted.get(5)),
+                    version: get_string(formatted.get(6)),
+                });
+            }
+
+            offset = strings_end + 2;
+        }
+
+        None
+    }
+}
+
+/// Copies the SMBIOS structure table, preferring the SMBIOS 3.0 entry point.
+fn read_structure_table() -> Option<Vec<u8>> {
+    let (entry_point_guid, entry_point) = uefi::system::with_config_table(|config_table| {
+        config_table.iter()
+            .find(|t| t.guid == SMBIOS3_TABLE_GUID)
+            .or_else(|| config_table.iter().find(|t| t.guid == SMBIOS_TABLE_GUID))
+            .map(|t| (t.guid, t.address as *const u8))
+    })?;
+
+    unsafe {
+        let (table_address, table_length) = if entry_point_guid == SMBIOS3_TABLE_GUID {
+            if core::slice::from_raw_parts(entry_point, 5) != b"_SM3_" {
+                return None;
+            }
+            (
+                (entry_point.add(0x10) as *const u64).read_unaligned(),
+                (entry_point.add(0x0C) as *const u32).read_unaligned() as usize
+            )
+        }
+        else {
+            if core::slice::from_raw_parts(entry_point, 4) != b"_SM_" {
+                return None;
+            }
+            (
+                (entry_point.add(0x18) as *const u32).read_unaligned() as u64,
+                (entry_point.add(0x16) as *const u16).read_unaligned() as usize
+            )
+        };
+
+        Some(core::slice::from_raw_parts(table_address as *const u8, table_length).to_vec())
+    }
+}
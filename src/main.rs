@@ -27,9 +27,13 @@ use uefi::proto::console::text::{Key, ScanCode};
 use boot::attempt_boot;
 use uefi::runtime::{get_variable, set_variable, ResetType, VariableAttributes, VariableVendor};
 use wtcore::config::*;
-use wtcore::config::load::{load_config, read_config};
-use wtcore::config::write::write_config;
+use wtcore::config::efivar::{restore_config_variable, store_config_variable};
+use wtcore::config::integrity::{editing_locked, enroll_config_hash};
+use wtcore::config::load::{find_config_password, load_config, loaded_from_fallback, locate_config, read_config};
+use wtcore::config::write::{set_default_entry, write_config};
 use wtcore::menu::{BootMenu, MenuOption};
+use wtcore::options::LOAD_OPTIONS;
+use wtcore::password::prompt_password;
 
 /// Entry point for the Wakatiwai bootloader.
 #[entry]
@@ -53,6 +57,32 @@ fn main() -> Status {
         Ok(config) => config,
         Err(err) => {
             eprintln!("Failed to load config: {}", err);
+            // A verified config cannot be fixed from the bootloader
+            if editing_locked() {
+                eprintln!("Config editing is disabled while the config is verified");
+                return exit();
+            }
+            // The edited config could not be written back if it is unclear which config file is in use
+            if locate_config().is_err() {
+                return exit();
+            }
+            if LOAD_OPTIONS.config_location.is_some() {
+                eprintln!("Config editing is disabled for a config on another partition");
+                return exit();
+            }
+            // The editor is protected by the password of any copy of the config which can still be read
+            match find_config_password() {
+                Ok(Some(password)) => {
+                    if !prompt_password(&password) {
+                        return exit();
+                    }
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    eprintln!("Config sets a malformed password, config editing is disabled");
+                    return exit();
+                }
+            }
             println_force!("Opening editor in 5 seconds...");
             uefi::boot::stall(5_000_000);
             edit_config();
@@ -75,6 +105,10 @@ fn main() -> Status {
                 None => {}
             };
         }
+        MenuOption::Submenu(_) => {
+            // Submenus are opened by the menu itself
+            unreachable!();
+        }
         MenuOption::Exit => {
             // Set colours because it's good if exiting to EDKII shell
             stdout!().set_color(
@@ -113,6 +147,46 @@ fn main() -> Status {
             edit_config();
             reboot();
         }
+        MenuOption::StoreConfig => {
+            match store_config_variable() {
+                Ok(_) => println_force!("Stored config in EFI variable"),
+                Err(err) => eprintln!("Failed to store config in EFI variable: {}", err)
+            }
+            uefi::boot::stall(2_000_000);
+            reboot();
+        }
+        MenuOption::RestoreConfig => {
+            match restore_config_variable() {
+                Ok(_) => println_force!("Restored config from EFI variable"),
+                Err(err) => eprintln!("Failed to restore config from EFI variable: {}", err)
+            }
+            uefi::boot::stall(2_000_000);
+            reboot();
+        }
+        MenuOption::TrustConfig => {
+            // The config file failed to load, so it must not be trusted
+            let result = if loaded_from_fallback() {
+                eprintln!("The config file could not be loaded, so it cannot be trusted");
+                Err(Status::ABORTED)
+            }
+            else {
+                read_config().and_then(|t| enroll_config_hash(&t))
+            };
+            match result {
+                Ok(_) => println_force!("Trusted current config"),
+                Err(err) => eprintln!("Failed to trust current config: {}", err)
+            }
+            uefi::boot::stall(2_000_000);
+            reboot();
+        }
+        MenuOption::SetDefault(entry) => {
+            match set_default_entry(&entry.name) {
+                Ok(_) => println_force!("Made \"{}\" the default boot entry", entry.name),
+                Err(err) => eprintln!("Failed to make \"{}\" the default boot entry: {}", entry.name, err)
+            }
+            uefi::boot::stall(2_000_000);
+            reboot();
+        }
         MenuOption::Reboot => {
             reboot();
         }
@@ -161,10 +235,17 @@ fn exit() -> Status {
 }
 
 fn edit_config() {
-    let mut editor = editor::Editor::new("wtconfig.json", &read_config().unwrap_or(vec![' ' as u8]));
+    let file_name = match locate_config() {
+        Ok((ConfigFormat::TOML, _)) => "wtconfig.toml",
+        _ => "wtconfig.json"
+    };
+    let mut editor = editor::Editor::new(file_name, &read_config().unwrap_or(vec![' ' as u8]));
     let editbuf = editor.edit();
     stdout!().clear().unwrap();
 
-    dprintln!("Writing config:\n{}", String::from_utf8(editbuf.clone()).unwrap());
-    write_config(&editbuf).unwrap();
+    dprintln!("Writing config:\n{}", String::from_utf8_lossy(&editbuf));
+    if let Err(err) = write_config(&editbuf) {
+        eprintln!("Failed to write config: {}", err);
+        uefi::boot::stall(2_000_000);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,156 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use spin::RwLock;
+use uefi::Guid;
+
+use crate::*;
+use crate::dev::smbios::SYSTEM_INFORMATION;
+use crate::dev::{DISK_GUID_HANDLE_MAPPING, DISK_SIGNATURE_HANDLE_MAPPING};
+use crate::wtcore::config::{BootCondition, BootEntry, CONFIG};
+use crate::wtcore::config::vars::expand_variables;
+use crate::wtcore::firmware::secure_boot_enabled;
+
+/// Results of previous file existence checks, keyed by the file's location.
+///
+/// Checking for a file requires invoking a file system driver, so this is only done once per file.
+static FILE_EXISTS_CACHE: RwLock<BTreeMap<FileKey, bool>> = RwLock::new(BTreeMap::new());
+
+/// Identifies a file on the partition of a boot entry, however the partition is addressed.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct FileKey {
+    disk_guid: Guid,
+    disk_signature: u32,
+    partition: u8,
+    part_uuid: Guid,
+    part_label: String,
+    part_type: Guid,
+    fs_uuid: String,
+    fs_label: String,
+    fstype: String,
+    path: String,
+}
+
+impl BootCondition {
+    /// Checks if all the conditions of a boot entry hold.
+    pub fn evaluate(&self, entry: &BootEntry) -> bool {
+        // Removable entries are only offered if their disk is present
+        if entry.removable {
+            let disk_present = if !entry.fs_uuid.is_empty() || !entry.fs_label.is_empty() {
+                boot::volume::get_volume_handle(entry).is_ok()
+            }
+            else if entry.disk_signature != 0 {
+                DISK_SIGNATURE_HANDLE_MAPPING.read().contains_key(&entry.disk_signature)
+            }
+            else if entry.disk_guid == Guid::ZERO && entry.part_uuid != Guid::ZERO {
+                dev::locate_partition(entry.part_uuid).is_some()
+            }
+            else {
+                DISK_GUID_HANDLE_MAPPING.read().contains_key(&entry.disk_guid)
+            };
+            if !disk_present {
+                return false;
+            }
+        }
+
+        if let Some(secure_boot) = self.secure_boot {
+            if secure_boot_enabled() != secure_boot {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.smbios_manufacturer {
+            if !glob_match(pattern, &SYSTEM_INFORMATION.manufacturer) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.smbios_product {
+            if !glob_match(pattern, &SYSTEM_INFORMATION.product) {
+                return false;
+            }
+        }
+        if let Some(disk_guid) = &self.disk_present {
+            if !DISK_GUID_HANDLE_MAPPING.read().contains_key(disk_guid) {
+                return false;
+            }
+        }
+        if let Some(path) = &self.file_exists {
+            if !file_exists(entry, path) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Forgets the results of previous file existence checks, e.g. once the disks have changed.
+pub fn clear_file_exists_cache() {
+    FILE_EXISTS_CACHE.write().clear();
+}
+
+/// Checks if a file exists on the partition of a boot entry.
+fn file_exists(entry: &BootEntry, path: &str) -> bool {
+    // The path may reference variables, just like the paths of the boot entry
+    let path = &expand_variables(path, entry, &CONFIG.read().variables);
+    let key = FileKey {
+        disk_guid: entry.disk_guid,
+        disk_signature: entry.disk_signature,
+        partition: entry.partition,
+        part_uuid: entry.part_uuid,
+        part_label: entry.part_label.clone(),
+        part_type: entry.part_type,
+        fs_uuid: entry.fs_uuid.clone(),
+        fs_label: entry.fs_label.clone(),
+        fstype: entry.fstype.clone(),
+        path: String::from(path)
+    };
+    if let Some(exists) = FILE_EXISTS_CACHE.read().get(&key) {
+        return *exists;
+    }
+
+    let entry = entry.expanded(&CONFIG.read().variables);
+    let exists = match boot::file_exists(&entry, path) {
+        Ok(ok) => ok,
+        Err(err) => {
+            dprintln!("Unable to check for condition file {} of \"{}\": {:?}", path, entry.name, err);
+            false
+        }
+    };
+
+    FILE_EXISTS_CACHE.write().insert(key, exists);
+    exists
+}
+
+/// Checks if a string matches a glob pattern, where `*` matches any number of characters and `?` matches exactly one.
+fn glob_match(pattern: &str, string: &str) -> bool {
+    let (pattern, string): (Vec<char>, Vec<char>) = (pattern.chars().collect(), string.chars().collect());
+    let (mut p, mut s) = (0, 0);
+    // Position of the last star in the pattern, and of the string when it was reached
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == string[s]) {
+            p += 1;
+            s += 1;
+        }
+        else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, s));
+            p += 1;
+        }
+        else if let Some((star_p, star_s)) = backtrack {
+            // Let the last star consume one more character
+            p = star_p + 1;
+            s = star_s + 1;
+            backtrack = Some((star_p, star_s + 1));
+        }
+        else {
+            return false;
+        }
+    }
+
+    // Any remaining pattern must consist of stars
+    pattern[p..].iter().all(|t| *t == '*')
+}
@@ -0,0 +1,317 @@
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use uefi::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol};
+use uefi::fs::{FileSystem, PathBuf};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::media::file::{File, FileAttribute, FileMode, RegularFile};
+use uefi::{CString16, Guid, Handle, Status};
+
+use crate::*;
+use crate::wtcore::config::{Autodetect, BootCondition, BootEntry};
+use crate::wtcore::config::bls::version_compare;
+
+/// Directory containing Unified Kernel Images on the ESP.
+const UKI_DIRECTORY: &str = "\\EFI\\Linux";
+/// Path of the Windows Boot Manager on the ESP.
+const WINDOWS_BOOT_MANAGER_PATH: &str = "\\EFI\\Microsoft\\Boot\\bootmgfw.efi";
+/// Vendor directories on the ESP which should not be searched for foreign loaders.
+const IGNORED_VENDOR_DIRECTORIES: [&str; 4] = ["BOOT", "LINUX", "MICROSOFT", "WAKATIWAI"];
+
+/// Generates boot entries for the programs found on the ESP.
+pub fn detect_entries(autodetect: &Autodetect) -> Vec<BootEntry> {
+    let mut ret = Vec::new();
+
+    // Generated entries are on the same partition as the bootloader
+    let (location, esp_handle) = match get_esp_handle().and_then(|t| Some((dev::locate_handle_partition(&t)?, t))) {
+        Some(some) => some,
+        None => {
+            wprintln!("Unable to locate the bootloader partition, skipping autodetection...");
+            return ret;
+        }
+    };
+    // The firmware can read the ESP, but generated entries are booted through a driver, so find one which reads it too
+    let fstype = match boot::volume::identify_file_system(&esp_handle) {
+        Some(some) => some,
+        None => {
+            wprintln!("No file system driver can read the bootloader partition, skipping autodetection...");
+            return ret;
+        }
+    };
+
+    let mut efifs = match uefi::boot::get_image_file_system(image_handle!()) {
+        Ok(ok) => FileSystem::new(ok),
+        Err(err) => {
+            eprintln!("Unable to read image file system: {:?}", err.status());
+            return ret;
+        }
+    };
+
+    if autodetect.uki {
+        ret.append(&mut detect_ukis(&mut efifs, &location, &fstype));
+    }
+    if autodetect.windows {
+        let path = to_path(WINDOWS_BOOT_MANAGER_PATH);
+        if efifs.try_exists(&path).unwrap_or(false) {
+            ret.push(efi_entry(String::from("Windows Boot Manager"), WINDOWS_BOOT_MANAGER_PATH, &location, &fstype));
+        }
+    }
+    if autodetect.efi {
+        ret.append(&mut detect_foreign_loaders(&mut efifs, &location, &fstype));
+    }
+
+    for entry in ret.iter() {
+        dprintln!("Detected boot entry: {}", entry);
+    }
+
+    ret
+}
+
+/// Generates boot entries for the Unified Kernel Images in `\EFI\Linux`, newest first.
+fn detect_ukis(efifs: &mut FileSystem, location: &(Guid, u8), fstype: &str) -> Vec<BootEntry> {
+    let mut ukis: Vec<(String, BootEntry)> = Vec::new();
+
+    for file_name in list_directory(efifs, UKI_DIRECTORY, false) {
+        if !file_name.to_uppercase().ends_with(".EFI") {
+            continue;
+        }
+
+        let mut path = String::from(UKI_DIRECTORY);
+        path.push('\\');
+        path.push_str(&file_name);
+
+        let sections = match read_pe_sections(&path, &[".osrel", ".uname", ".cmdline"]) {
+            Ok(ok) => ok,
+            Err(err) => {
+                wprintln!("Unable to read UKI {}: {:?}", file_name, err);
+                continue;
+            }
+        };
+        let (osrel, uname, cmdline) = (&sections[0], &sections[1], &sections[2]);
+
+        // Prefer the pretty name of the OS, then its plain name, then the file name
+        let mut name = match os_release_value(osrel, "PRETTY_NAME").or_else(|| os_release_value(osrel, "NAME")) {
+            Some(some) => some,
+            None => file_name.clone()
+        };
+        let version = match uname {
+            Some(some) => Some(some.trim().to_string()),
+            None => os_release_value(osrel, "VERSION_ID")
+        };
+        if let Some(version) = &version {
+            name = format!("{} ({})", name, version);
+        }
+
+        let mut entry = efi_entry(name, &path, location, fstype);
+        entry.args = cmdline.clone().unwrap_or_default().trim().to_string();
+        ukis.push((version.unwrap_or(file_name), entry));
+    }
+
+    ukis.sort_by(|a, b| version_compare(&b.0, &a.0));
+    ukis.into_iter().map(|t| t.1).collect()
+}
+
+/// Generates boot entries for the EFI programs in the vendor directories of the ESP.
+fn detect_foreign_loaders(efifs: &mut FileSystem, location: &(Guid, u8), fstype: &str) -> Vec<BootEntry> {
+    let mut ret = Vec::new();
+
+    for vendor in list_directory(efifs, "\\EFI", true) {
+        if IGNORED_VENDOR_DIRECTORIES.contains(&vendor.to_uppercase().as_str()) {
+            continue;
+        }
+
+        let mut vendor_path = String::from("\\EFI\\");
+        vendor_path.push_str(&vendor);
+        for file_name in list_directory(efifs, &vendor_path, false) {
+            if !file_name.to_uppercase().ends_with(".EFI") {
+                continue;
+            }
+
+            let mut path = vendor_path.clone();
+            path.push('\\');
+            path.push_str(&file_name);
+            ret.push(efi_entry(format!("{} ({})", vendor, file_name), &path, location, fstype));
+        }
+    }
+
+    ret
+}
+
+/// Creates a boot entry for an EFI program on the ESP, which is read using the given file system driver.
+fn efi_entry(name: String, path: &str, location: &(Guid, u8), fstype: &str) -> BootEntry {
+    BootEntry {
+        name: BootEntry::truncate_name(&name),
+        extends: String::new(),
+        removable: false,
+        hidden: false,
+        locked: false,
+        condition: BootCondition::default(),
+        disk_guid: location.0,
+        disk_signature: 0,
+        partition: location.1,
+        part_uuid: Guid::ZERO,
+        part_label: String::new(),
+        part_type: Guid::ZERO,
+        fs_uuid: String::new(),
+        fs_label: String::new(),
+        fstype: fstype.to_string(),
+        ostype: String::from("UEFI"),
+        path: path.replace('\\', "/"),
+        initrd: Vec::new(),
+        args: String::new(),
+        generated: true
+    }
+}
+
+/// Finds the handle of the partition the bootloader was loaded from.
+fn get_esp_handle() -> Option<Handle> {
+    let loaded_image: ScopedProtocol<LoadedImage>;
+    unsafe {
+        match uefi::boot::open_protocol::<LoadedImage>(
+            OpenProtocolParams {
+                handle: image_handle!(),
+                agent: image_handle!(),
+                controller: None
+            },
+            OpenProtocolAttributes::GetProtocol
+        ) {
+            Ok(ok) => {
+                loaded_image = ok;
+            }
+            Err(_) => {
+                return None;
+            }
+        }
+    }
+
+    loaded_image.device()
+}
+
+/// Lists the names of the files (or directories) in a directory of the ESP.
+fn list_directory(efifs: &mut FileSystem, path: &str, directories: bool) -> Vec<String> {
+    let mut ret = Vec::new();
+
+    let path = to_path(path);
+    if !efifs.try_exists(&path).unwrap_or(false) {
+        return ret;
+    }
+
+    let directory = match efifs.read_dir(&path) {
+        Ok(ok) => ok,
+        Err(_) => return ret
+    };
+    for file_info in directory {
+        let file_info = match file_info {
+            Ok(ok) => ok,
+            Err(_) => continue
+        };
+
+        let file_name = file_info.file_name().to_string();
+        if file_name == "." || file_name == ".." || file_info.is_directory() != directories {
+            continue;
+        }
+        ret.push(file_name);
+    }
+
+    ret
+}
+
+/// Reads the contents of the named sections of a PE image on the ESP, without reading the entire image.
+fn read_pe_sections(path: &str, names: &[&str]) -> Result<Vec<Option<String>>, Status> {
+    let mut ret = vec![None; names.len()];
+
+    let mut sfs = match uefi::boot::get_image_file_system(image_handle!()) {
+        Ok(ok) => ok,
+        Err(err) => return Err(err.status())
+    };
+    let mut file: RegularFile = match sfs.open_volume() {
+        Ok(mut root) => {
+            match root.open(
+                &CString16::try_from(path).unwrap(),
+                FileMode::Read,
+                FileAttribute::empty()
+            ) {
+                Ok(ok) => match ok.into_regular_file() {
+                    Some(some) => some,
+                    None => return Err(Status::INVALID_PARAMETER)
+                },
+                Err(err) => return Err(err.status())
+            }
+        }
+        Err(err) => return Err(err.status())
+    };
+
+    // Locate the PE header from the DOS header
+    let dos_header = read_file_bytes(&mut file, 0, 0x40)?;
+    if dos_header[0..2] != *b"MZ" {
+        return Err(Status::LOAD_ERROR);
+    }
+    let pe_offset = u32::from_le_bytes(dos_header[0x3C..0x40].try_into().unwrap()) as u64;
+
+    // Read the COFF header which follows the PE signature
+    let pe_header = read_file_bytes(&mut file, pe_offset, 24)?;
+    if pe_header[0..4] != *b"PE\0\0" {
+        return Err(Status::LOAD_ERROR);
+    }
+    let section_count = u16::from_le_bytes(pe_header[6..8].try_into().unwrap()) as usize;
+    let optional_header_size = u16::from_le_bytes(pe_header[20..22].try_into().unwrap()) as u64;
+
+    // Each section header is 40 bytes long and directly follows the optional header
+    const SECTION_HEADER_SIZE: usize = 40;
+    let section_table = read_file_bytes(
+        &mut file,
+        pe_offset + 24 + optional_header_size,
+        section_count * SECTION_HEADER_SIZE
+    )?;
+    for section_header in section_table.chunks_exact(SECTION_HEADER_SIZE) {
+        let section_name = String::from_utf8_lossy(&section_header[0..8]);
+        let section_name = section_name.trim_end_matches('\0');
+        let idx = match names.iter().position(|t| *t == section_name) {
+            Some(some) => some,
+            None => continue
+        };
+
+        let virtual_size = u32::from_le_bytes(section_header[8..12].try_into().unwrap());
+        let raw_size = u32::from_le_bytes(section_header[16..20].try_into().unwrap());
+        let raw_offset = u32::from_le_bytes(section_header[20..24].try_into().unwrap());
+        let data = read_file_bytes(&mut file, raw_offset as u64, core::cmp::min(virtual_size, raw_size) as usize)?;
+
+        ret[idx] = Some(String::from_utf8_lossy(&data).trim_end_matches('\0').to_string());
+    }
+
+    Ok(ret)
+}
+
+/// Reads a number of bytes from a given position in a file.
+fn read_file_bytes(file: &mut RegularFile, position: u64, count: usize) -> Result<Vec<u8>, Status> {
+    let mut buffer = vec![0 as u8; count];
+
+    if let Err(err) = file.set_position(position) {
+        return Err(err.status());
+    }
+    match file.read(&mut buffer) {
+        Ok(read) if read == count => Ok(buffer),
+        Ok(_) => Err(Status::END_OF_FILE),
+        Err(err) => Err(err.status())
+    }
+}
+
+/// Gets the (unquoted) value of a key from the contents of an os-release file.
+fn os_release_value(osrel: &Option<String>, key: &str) -> Option<String> {
+    for line in osrel.as_ref()?.lines() {
+        if let Some((line_key, value)) = line.split_once('=') {
+            if line_key.trim() == key {
+                return Some(value.trim().trim_matches(|c| c == '"' || c == '\'').to_string());
+            }
+        }
+    }
+
+    None
+}
+
+/// Converts a string to a path on the ESP.
+fn to_path(path: &str) -> PathBuf {
+    PathBuf::from(CString16::try_from(path).unwrap())
+}
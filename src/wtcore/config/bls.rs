@@ -0,0 +1,352 @@
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use uefi::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol, SearchType};
+use uefi::fs::{FileSystem, PathBuf};
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::{CString16, Guid, Handle, Identify};
+
+use crate::*;
+use crate::wtcore::config::{BootCondition, BootEntry, Initrd};
+
+/// Directory containing Boot Loader Specification Type #1 entries, relative to a partition root.
+const BLS_ENTRIES_PATH: &str = "\\loader\\entries";
+/// File extension of Boot Loader Specification Type #1 entries.
+const BLS_ENTRY_EXTENSION: &str = ".conf";
+
+/// Describes a Boot Loader Specification Type #1 entry.
+/// [Specification](https://uapi-group.org/specifications/specs/boot_loader_specification/#type-1-boot-loader-entry-keys).
+#[derive(Debug, Default)]
+struct BLSEntry {
+    /// The file name of the entry, without the `.conf` suffix.
+    id: String,
+    /// Human-readable name of the entry.
+    title: String,
+    /// Human-readable version of the entry.
+    version: String,
+    /// The machine ID of the OS this entry belongs to.
+    machine_id: String,
+    /// Key used to order entries in the menu.
+    sort_key: String,
+    /// Path of the Linux kernel to boot.
+    linux: String,
+    /// Paths of the initial ramdisks to use.
+    initrd: Vec<String>,
+    /// Path of an EFI program to boot.
+    efi: String,
+    /// Kernel command line or EFI program arguments.
+    options: String
+}
+
+impl BLSEntry {
+    /// Parses the contents of a `.conf` file.
+    fn parse(id: &str, content: &str) -> Self {
+        let mut ret = BLSEntry {
+            id: id.to_string(),
+            ..Default::default()
+        };
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // Keys are separated from values by any amount of whitespace
+            let (key, value) = match line.split_once(|c: char| c.is_ascii_whitespace()) {
+                Some((key, value)) => (key, value.trim()),
+                None => (line, "")
+            };
+
+            match key {
+                "title"         => ret.title = value.to_string(),
+                "version"       => ret.version = value.to_string(),
+                "machine-id"    => ret.machine_id = value.to_string(),
+                "sort-key"      => ret.sort_key = value.to_string(),
+                "linux"         => ret.linux = value.to_string(),
+                "initrd"        => ret.initrd.push(value.to_string()),
+                "efi"           => ret.efi = value.to_string(),
+                "options"       => {
+                    // Multiple options lines are concatenated
+                    if !ret.options.is_empty() {
+                        ret.options.push(' ');
+                    }
+                    ret.options.push_str(value);
+                }
+                _ => {
+                    dprintln!("Ignoring unsupported key \"{}\" in BLS entry {}", key, id);
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Compares two entries in the order systemd-boot would display them.
+    fn compare(&self, other: &Self) -> Ordering {
+        // Entries with a sort key go first
+        match (self.sort_key.is_empty(), other.sort_key.is_empty()) {
+            (false, true) => return Ordering::Less,
+            (true, false) => return Ordering::Greater,
+            (false, false) => {
+                let ordering = self.sort_key.cmp(&other.sort_key)
+                    .then_with(|| self.machine_id.cmp(&other.machine_id))
+                    .then_with(|| version_compare(&other.version, &self.version));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (true, true) => {}
+        }
+
+        // Newest first
+        version_compare(&other.id, &self.id)
+    }
+
+    /// Converts the entry to a boot entry on the given partition, read with the given file system driver.
+    fn to_boot_entry(&self, location: &(uefi::Guid, u8), fstype: &str) -> Option<BootEntry> {
+        let (ostype, path) = if !self.linux.is_empty() {
+            ("Linux", &self.linux)
+        }
+        else if !self.efi.is_empty() {
+            ("UEFI", &self.efi)
+        }
+        else {
+            wprintln!("BLS entry {} has neither a linux nor an efi key, ignoring...", self.id);
+            return None;
+        };
+
+        // Prefer the title, but make sure two kernels of the same OS can be told apart
+        let mut name = if self.title.is_empty() { self.id.clone() } else { self.title.clone() };
+        if !self.version.is_empty() {
+            name = format!("{} ({})", name, self.version);
+        }
+
+        Some(BootEntry {
+            name: BootEntry::truncate_name(&name),
+            extends: String::new(),
+            removable: false,
+            hidden: false,
+            locked: false,
+            condition: BootCondition::default(),
+            disk_guid: location.0,
+            disk_signature: 0,
+            partition: location.1,
+            part_uuid: Guid::ZERO,
+            part_label: String::new(),
+            part_type: Guid::ZERO,
+            fs_uuid: String::new(),
+            fs_label: String::new(),
+            fstype: fstype.to_string(),
+            ostype: ostype.to_string(),
+            path: path.replace('\\', "/"),
+            initrd: self.initrd.iter().map(|t| Initrd::Path(t.replace('\\', "/"))).collect(),
+            args: self.options.clone(),
+            generated: true
+        })
+    }
+}
+
+/// Reads the Boot Loader Specification Type #1 entries on every partition readable by the firmware, ordered across all partitions.
+pub fn read_bls_entries() -> Vec<BootEntry> {
+    let mut ret = Vec::new();
+    let mut bls_entries: Vec<(BLSEntry, (Guid, u8), String)> = Vec::new();
+
+    let fs_handles: Vec<Handle> = match uefi::boot::locate_handle_buffer(
+        SearchType::ByProtocol(&SimpleFileSystem::GUID)
+    ) {
+        Ok(ok) => ok.iter().copied().collect(),
+        Err(err) => {
+            dprintln!("Unable to locate file systems for BLS entries: {:?}", err.status());
+            return ret;
+        }
+    };
+
+    for fs_handle in fs_handles.iter() {
+        // Entries must be bootable, so the partition they are on needs to be addressable
+        let location = match dev::locate_handle_partition(fs_handle) {
+            Some(some) => some,
+            None => {
+                dprintln!("Unable to locate partition of file system handle {:#010x}", fs_handle.as_ptr() as u64);
+                continue;
+            }
+        };
+
+        let partition_bls_entries = read_partition_bls_entries(fs_handle);
+        if partition_bls_entries.is_empty() {
+            continue;
+        }
+
+        // The firmware can read the partition, but it is booted through a driver, so find one which reads it too
+        let fstype = match boot::volume::identify_file_system(fs_handle) {
+            Some(some) => some,
+            None => {
+                wprintln!("No file system driver can read the BLS entries on partition {} of disk {}, ignoring...", location.1, location.0);
+                continue;
+            }
+        };
+
+        for bls_entry in partition_bls_entries {
+            bls_entries.push((bls_entry, location, fstype.clone()));
+        }
+    }
+
+    bls_entries.sort_by(|a, b| a.0.compare(&b.0));
+    for (bls_entry, location, fstype) in bls_entries.iter() {
+        if let Some(bootentry) = bls_entry.to_boot_entry(location, fstype) {
+            dprintln!("Detected BLS entry: {}", bootentry);
+            ret.push(bootentry);
+        }
+    }
+
+    ret
+}
+
+/// Reads the Boot Loader Specification Type #1 entries of a single file system.
+fn read_partition_bls_entries(fs_handle: &Handle) -> Vec<BLSEntry> {
+    let mut ret = Vec::new();
+
+    let protocol: ScopedProtocol<SimpleFileSystem>;
+    unsafe {
+        match uefi::boot::open_protocol::<SimpleFileSystem>(
+            OpenProtocolParams {
+                handle: *fs_handle,
+                agent: image_handle!(),
+                controller: None
+            },
+            OpenProtocolAttributes::GetProtocol
+        ) {
+            Ok(ok) => {
+                protocol = ok;
+            }
+            Err(_) => {
+                return ret;
+            }
+        }
+    }
+    let mut efifs = FileSystem::new(protocol);
+
+    let entries_path = PathBuf::from(CString16::try_from(BLS_ENTRIES_PATH).unwrap());
+    if !efifs.try_exists(&entries_path).unwrap_or(false) {
+        return ret;
+    }
+
+    let directory = match efifs.read_dir(&entries_path) {
+        Ok(ok) => ok,
+        Err(_) => {
+            wprintln!("Unable to read BLS entries directory");
+            return ret;
+        }
+    };
+
+    for file_info in directory {
+        let file_info = match file_info {
+            Ok(ok) => ok,
+            Err(_) => continue
+        };
+        if file_info.is_directory() {
+            continue;
+        }
+
+        let file_name = file_info.file_name().to_string();
+        let id = match file_name.strip_suffix(BLS_ENTRY_EXTENSION) {
+            Some(some) => some,
+            None => continue
+        };
+
+        let mut file_path = String::from(BLS_ENTRIES_PATH);
+        file_path.push('\\');
+        file_path.push_str(&file_name);
+        let content = match efifs.read(PathBuf::from(CString16::try_from(file_path.as_str()).unwrap())) {
+            Ok(ok) => ok,
+            Err(_) => {
+                wprintln!("Unable to read BLS entry {}", file_name);
+                continue;
+            }
+        };
+
+        match String::from_utf8(content) {
+            Ok(ok) => ret.push(BLSEntry::parse(id, &ok)),
+            Err(_) => {
+                wprintln!("BLS entry {} is not valid UTF-8, ignoring...", file_name);
+            }
+        }
+    }
+
+    ret
+}
+
+/// Compares two version strings, in the manner of systemd's `strverscmp_improved`.
+///
+/// Numeric segments are compared by value, alphabetic segments lexically, and a tilde sorts before anything else (including the end of the string).
+pub fn version_compare(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+
+    loop {
+        // Skip separators
+        let is_separator = |c: &u8| !c.is_ascii_alphanumeric() && *c != b'~';
+        while a.first().is_some_and(is_separator) {
+            a = &a[1..];
+        }
+        while b.first().is_some_and(is_separator) {
+            b = &b[1..];
+        }
+
+        // Tildes sort before everything
+        match (a.first() == Some(&b'~'), b.first() == Some(&b'~')) {
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            (true, true) => {
+                a = &a[1..];
+                b = &b[1..];
+                continue;
+            }
+            (false, false) => {}
+        }
+
+        // Whichever string has more segments is newer
+        match (a.is_empty(), b.is_empty()) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            (false, false) => {}
+        }
+
+        let ordering = if a[0].is_ascii_digit() {
+            if !b[0].is_ascii_digit() {
+                // Numbers are newer than letters
+                return Ordering::Greater;
+            }
+
+            let a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+            let b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+            let (a_num, b_num) = (&a[..a_len], &b[..b_len]);
+            a = &a[a_len..];
+            b = &b[b_len..];
+
+            // Compare by value, ignoring leading zeros
+            let a_num = &a_num[a_num.iter().take_while(|c| **c == b'0').count()..];
+            let b_num = &b_num[b_num.iter().take_while(|c| **c == b'0').count()..];
+            a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num))
+        }
+        else {
+            if b[0].is_ascii_digit() {
+                return Ordering::Less;
+            }
+
+            let a_len = a.iter().take_while(|c| c.is_ascii_alphabetic()).count();
+            let b_len = b.iter().take_while(|c| c.is_ascii_alphabetic()).count();
+            let ordering = a[..a_len].cmp(&b[..b_len]);
+            a = &a[a_len..];
+            b = &b[b_len..];
+            ordering
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
@@ -0,0 +1,73 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use uefi::runtime::VariableAttributes;
+use uefi::{cstr16, CStr16, Status};
+
+use crate::*;
+use crate::wtcore::config::*;
+use crate::wtcore::config::integrity::verify_config;
+use crate::wtcore::config::load::{locate_config, read_verified_config};
+use crate::wtcore::firmware::WAKATIWAI_VENDOR;
+
+/// Name of the EFI variable holding a copy of the bootloader configuration.
+const CONFIG_VARIABLE_NAME: &CStr16 = cstr16!("WakatiwaiConfig");
+
+/// Stores the bootloader configuration file in its EFI variable.
+pub fn store_config_variable() -> Result<(), Status> {
+    write_config_variable(&read_verified_config()?.1)
+}
+
+/// Restores the bootloader configuration file from its EFI variable.
+pub fn restore_config_variable() -> Result<(), Status> {
+    let buffer = read_config_variable()?;
+    if let Err(err) = verify_config(&buffer) {
+        eprintln!("Config in EFI variable failed verification");
+        return Err(err);
+    }
+
+    // The variable holds the config as written, so it can only replace a config file of the same format
+    let format = ConfigFormat::detect(&buffer);
+    if locate_config()?.0 != format {
+        eprintln!("Config in EFI variable is written in {:?}, which does not match the config file", format);
+        return Err(Status::UNSUPPORTED);
+    }
+
+    write::write_config(&buffer)
+}
+
+/// Reads the copy of the bootloader configuration from its EFI variable.
+pub fn read_config_variable() -> Result<Vec<u8>, Status> {
+    match uefi::runtime::get_variable_boxed(CONFIG_VARIABLE_NAME, &WAKATIWAI_VENDOR) {
+        Ok((ok, _)) => Ok(ok.to_vec()),
+        Err(err) => {
+            if err.status() != Status::NOT_FOUND {
+                eprintln!("Failed to read EFI variable {}: {:?}", CONFIG_VARIABLE_NAME, err.status());
+            }
+            Err(err.status())
+        }
+    }
+}
+
+/// Stores a configuration in its EFI variable as written, so it can be verified when it is loaded.
+///
+/// The variable is left untouched if it already holds the same configuration.
+pub fn write_config_variable(buffer: &[u8]) -> Result<(), Status> {
+    if read_config_variable().is_ok_and(|t| t == buffer) {
+        return Ok(());
+    }
+
+    match uefi::runtime::set_variable(
+        CONFIG_VARIABLE_NAME,
+        &WAKATIWAI_VENDOR,
+        VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        buffer
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            eprintln!("Failed to write EFI variable {}: {:?}", CONFIG_VARIABLE_NAME, err.status());
+            Err(err.status())
+        }
+    }
+}
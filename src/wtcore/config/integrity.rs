@@ -0,0 +1,101 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use uefi::runtime::VariableAttributes;
+use uefi::{cstr16, CStr16, Status};
+
+use crate::*;
+use crate::wtcore::crypto::{decode_hex, sha256, SHA256_LENGTH};
+use crate::wtcore::firmware::{secure_boot_enabled, WAKATIWAI_VENDOR};
+
+/// Name of the EFI variable holding the SHA-256 hash of the trusted bootloader configuration file.
+const CONFIG_HASH_VARIABLE_NAME: &CStr16 = cstr16!("WakatiwaiConfigHash");
+
+/// The hex-encoded SHA-256 hash of the trusted bootloader configuration file, if embedded at build time.
+const BUILD_CONFIG_HASH: Option<&str> = option_env!("WAKATIWAI_CONFIG_HASH");
+
+/// Verifies a configuration file against the trusted hashes.
+///
+/// If no hash is trusted, the configuration file is only accepted if Secure Boot is disabled.
+pub fn verify_config(buffer: &[u8]) -> Result<(), Status> {
+    let mut trusted_hashes: Vec<[u8; SHA256_LENGTH]> = Vec::new();
+    if let Some(build_config_hash) = BUILD_CONFIG_HASH {
+        match decode_hex(build_config_hash).and_then(|t| t.try_into().ok()) {
+            Some(some) => trusted_hashes.push(some),
+            None => {
+                // Fail closed, the embedded hash was meant to restrict the config
+                eprintln!("Config hash embedded at build time is malformed");
+                return Err(Status::SECURITY_VIOLATION);
+            }
+        }
+    }
+    if let Some(enrolled_hash) = read_enrolled_hash() {
+        trusted_hashes.push(enrolled_hash);
+    }
+
+    if trusted_hashes.is_empty() {
+        if secure_boot_enabled() {
+            eprintln!("Refusing to load a config with no trusted hash while Secure Boot is enabled");
+            return Err(Status::SECURITY_VIOLATION);
+        }
+        return Ok(());
+    }
+
+    if !trusted_hashes.contains(&sha256(buffer)) {
+        eprintln!("Config does not match its trusted hash, refusing to load it");
+        return Err(Status::SECURITY_VIOLATION);
+    }
+
+    dprintln!("Config matches its trusted hash");
+    Ok(())
+}
+
+/// Checks if the configuration must not be changed from the bootloader.
+///
+/// This is the case under Secure Boot, or if the trusted hash is embedded at build time and so cannot follow any changes.
+pub fn editing_locked() -> bool {
+    secure_boot_enabled() || BUILD_CONFIG_HASH.is_some()
+}
+
+/// Checks if the configuration file is verified, i.e. a hash is trusted or Secure Boot is enabled.
+pub fn verification_enabled() -> bool {
+    editing_locked() || hash_enrolled()
+}
+
+/// Checks if a hash of the configuration file has been enrolled in its EFI variable.
+pub fn hash_enrolled() -> bool {
+    read_enrolled_hash().is_some()
+}
+
+/// Trusts a configuration file by enrolling its hash in an EFI variable.
+///
+/// The variable is not accessible at runtime, so it can only be changed from the bootloader (or another boot-time program).
+pub fn enroll_config_hash(buffer: &[u8]) -> Result<(), Status> {
+    match uefi::runtime::set_variable(
+        CONFIG_HASH_VARIABLE_NAME,
+        &WAKATIWAI_VENDOR,
+        VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS,
+        &sha256(buffer)
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            eprintln!("Failed to write EFI variable {}: {:?}", CONFIG_HASH_VARIABLE_NAME, err.status());
+            Err(err.status())
+        }
+    }
+}
+
+/// Reads the hash enrolled in the EFI variable.
+fn read_enrolled_hash() -> Option<[u8; SHA256_LENGTH]> {
+    let mut buffer = [0 as u8; SHA256_LENGTH];
+    let (hash, attributes) = uefi::runtime::get_variable(CONFIG_HASH_VARIABLE_NAME, &WAKATIWAI_VENDOR, &mut buffer).ok()?;
+
+    // A variable accessible at runtime could have been written by the OS, so it cannot be trusted
+    if attributes.contains(VariableAttributes::RUNTIME_ACCESS) {
+        wprintln!("Ignoring EFI variable {} as it is accessible at runtime", CONFIG_HASH_VARIABLE_NAME);
+        return None;
+    }
+
+    hash.try_into().ok()
+}
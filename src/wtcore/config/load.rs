@@ -1,24 +1,262 @@
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use microjson::{JSONValue, JSONValueType};
 use uefi::fs::FileSystem;
 use uefi::prelude::*;
+use uefi::{CStr16, CString16};
 
 use crate::*;
 use crate::wtcore::config::*;
+use crate::wtcore::config::efivar::{read_config_variable, write_config_variable};
+use crate::wtcore::config::integrity::verify_config;
 use crate::wtcore::config::parse::parse_config;
+use crate::wtcore::config::toml::toml_to_json;
+use crate::wtcore::config::write::{rename_file, temp_config_path};
+use crate::wtcore::options::LOAD_OPTIONS;
+use crate::wtcore::password::PasswordHash;
 
-/// Loads the bootloader configuration file.
+/// Determines if the configuration was loaded from the last-known-good backup because the configuration file could not be loaded.
+pub static LOADED_FROM_BACKUP: AtomicBool = AtomicBool::new(false);
+/// Determines if the configuration was loaded from its EFI variable because neither the configuration file nor its backup could be loaded.
+pub static LOADED_FROM_VARIABLE: AtomicBool = AtomicBool::new(false);
+
+/// Checks if the configuration was loaded from a fallback copy, rather than from the configuration file.
+pub fn loaded_from_fallback() -> bool {
+    LOADED_FROM_BACKUP.load(Ordering::Relaxed) || LOADED_FROM_VARIABLE.load(Ordering::Relaxed)
+}
+
+/// Loads the bootloader configuration file, which may be written in JSON or TOML.
+///
+/// If the configuration file cannot be read, verified, or parsed, the last-known-good backup is loaded instead,
+/// followed by the copy of the configuration in its EFI variable. Both copies are kept as written, so they must pass the same verification.
 pub fn load_config() -> Result<(), Status> {
     println_force!("Loading config...");
 
-    parse_config(match read_config() {
-        Ok(ok) => ok,
-        Err(err) => {
-            return Err(err);
+    // Load options may override the config, so make sure they are read before it is loaded
+    spin::Lazy::force(&LOAD_OPTIONS);
+
+    recover_config();
+
+    let err = match read_verified_config() {
+        Ok((format, buffer)) => {
+            match config_to_json(format, &buffer).and_then(parse_config) {
+                Ok(_) => {
+                    // The config is known to be good, so keep a copy of it
+                    backup_config(format, &buffer);
+                    if CONFIG.read().nv_config {
+                        let _ = write_config_variable(&buffer);
+                    }
+                    return Ok(());
+                }
+                Err(err) => err
+            }
+        }
+        Err(err) => err
+    };
+
+    eprintln!("Failed to load config, attempting to load last-known-good backup...");
+    for format in backup_formats() {
+        if let Ok(backup) = read_config_file(format.backup_path()) {
+            if load_config_copy(format, &backup).is_ok() {
+                LOADED_FROM_BACKUP.store(true, Ordering::Relaxed);
+                return Ok(());
+            }
+        }
+    }
+
+    eprintln!("Failed to load backup, attempting to load config from EFI variable...");
+    if let Ok(variable) = read_config_variable() {
+        if load_config_copy(ConfigFormat::detect(&variable), &variable).is_ok() {
+            LOADED_FROM_VARIABLE.store(true, Ordering::Relaxed);
+            return Ok(());
+        }
+    }
+
+    Err(err)
+}
+
+/// Finishes replacing a configuration file which was lost while being written by `write_config`.
+///
+/// The configuration file is only removed once the temporary file replacing it has been written in full,
+/// so if no configuration file exists, a temporary file which passes verification is renamed into its place.
+fn recover_config() {
+    // Configs on other partitions are never written
+    if LOAD_OPTIONS.config_location.is_some() {
+        return;
+    }
+
+    let config_paths: Vec<CString16> = match &LOAD_OPTIONS.config_path {
+        Some(some) => vec![some.clone()],
+        None => vec![ConfigFormat::JSON.path().into(), ConfigFormat::TOML.path().into()]
+    };
+
+    let mut efifs = match uefi::boot::get_image_file_system(image_handle!()) {
+        Ok(ok) => FileSystem::new(ok),
+        Err(_) => return
+    };
+    if config_paths.iter().any(|t| efifs.try_exists(&**t).unwrap_or(true)) {
+        return;
+    }
+
+    for config_path in config_paths {
+        let temp_path = match temp_config_path(&config_path) {
+            Ok(ok) => ok,
+            Err(_) => continue
+        };
+        match efifs.read(&*temp_path) {
+            Ok(ok) if verify_config(&ok).is_ok() => {}
+            _ => continue
         }
-    })
+
+        // The file system is opened exclusively, so it must be closed before the file can be renamed
+        drop(efifs);
+        wprintln!("Config file is missing, restoring it from {}", temp_path);
+        if rename_file(&temp_path, &config_path).is_err() {
+            eprintln!("Failed to restore config file from {}", temp_path);
+        }
+        return;
+    }
+}
+
+/// Determines which last-known-good backups may be loaded in place of the configuration file, in order of preference.
+///
+/// Only the backup of the configuration file in use is loaded. If neither configuration file exists, its format is unknown, so both backups are tried.
+fn backup_formats() -> Vec<ConfigFormat> {
+    if let Some(location) = &LOAD_OPTIONS.config_location {
+        return vec![ConfigFormat::from_path(&location.path)];
+    }
+    if let Some(config_path) = &LOAD_OPTIONS.config_path {
+        return vec![ConfigFormat::from_path(&config_path.to_string())];
+    }
+
+    let mut efifs = match uefi::boot::get_image_file_system(image_handle!()) {
+        Ok(ok) => FileSystem::new(ok),
+        Err(_) => return vec![ConfigFormat::JSON, ConfigFormat::TOML]
+    };
+    match (efifs.try_exists(ConfigFormat::JSON.path()).unwrap_or(false), efifs.try_exists(ConfigFormat::TOML.path()).unwrap_or(false)) {
+        (true, false) => vec![ConfigFormat::JSON],
+        (false, true) => vec![ConfigFormat::TOML],
+        _ => vec![ConfigFormat::JSON, ConfigFormat::TOML]
+    }
+}
+
+/// Finds the password set by any readable copy of the configuration, for when none of them could be loaded.
+///
+/// The last-known-good backups and the copy in the EFI variable are preferred, as the configuration file is the copy which failed to load.
+/// Fails if a copy sets a password which cannot be parsed, as that password must not be bypassed either.
+pub fn find_config_password() -> Result<Option<PasswordHash>, Status> {
+    let mut copies = Vec::new();
+    for format in [ConfigFormat::JSON, ConfigFormat::TOML] {
+        if let Ok(backup) = read_config_file(format.backup_path()) {
+            copies.push((format, backup));
+        }
+    }
+    if let Ok(variable) = read_config_variable() {
+        copies.push((ConfigFormat::detect(&variable), variable));
+    }
+    if let Ok(config) = read_config() {
+        copies.push((ConfigFormat::detect(&config), config));
+    }
+
+    for (format, buffer) in copies {
+        let buffer_string = match config_to_json(format, &buffer).map(String::from_utf8) {
+            Ok(Ok(ok)) => ok,
+            _ => continue
+        };
+        let json = match JSONValue::load_and_verify(&buffer_string) {
+            Ok(ok) => ok,
+            Err(_) => continue
+        };
+        match json.get_key_value(Config::KEY_PASSWORD) {
+            Ok(password_json) if password_json.value_type == JSONValueType::String => {
+                match PasswordHash::parse(password_json.read_string().unwrap()) {
+                    Some(some) => return Ok(Some(some)),
+                    None => return Err(Status::COMPROMISED_DATA)
+                }
+            }
+            Ok(_) => return Err(Status::COMPROMISED_DATA),
+            Err(_) => {}
+        }
+    }
+
+    Ok(None)
 }
 
 /// Attempts to read the bootloader configuration file and returns a byte vector containing the file data on a success.
 pub fn read_config() -> Result<Vec<u8>, Status> {
+    match &LOAD_OPTIONS.config_location {
+        Some(location) => read_config_location(location),
+        None => read_config_file(&locate_config()?.1)
+    }
+}
+
+/// Attempts to read and verify the bootloader configuration file, returning its format and its contents as written.
+pub fn read_verified_config() -> Result<(ConfigFormat, Vec<u8>), Status> {
+    let (format, buffer) = match &LOAD_OPTIONS.config_location {
+        Some(location) => (ConfigFormat::from_path(&location.path), read_config_location(location)?),
+        None => {
+            let (format, path) = locate_config()?;
+            (format, read_config_file(&path)?)
+        }
+    };
+
+    // The file is verified as written, before it is converted
+    verify_config(&buffer)?;
+
+    Ok((format, buffer))
+}
+
+/// Converts a configuration written in the given format to JSON.
+fn config_to_json(format: ConfigFormat, buffer: &[u8]) -> Result<Vec<u8>, Status> {
+    match format {
+        ConfigFormat::JSON => Ok(buffer.to_vec()),
+        ConfigFormat::TOML => Ok(toml_to_json(buffer)?.into_bytes())
+    }
+}
+
+/// Attempts to verify and parse a copy of a configuration written in the given format.
+fn load_config_copy(format: ConfigFormat, buffer: &[u8]) -> Result<(), Status> {
+    verify_config(buffer)?;
+    parse_config(config_to_json(format, buffer)?)
+}
+
+/// Determines the format and path of the bootloader configuration file.
+///
+/// A path given in the load options is used as-is, with its format determined by its extension.
+/// Otherwise, the format is determined by which configuration file exists, and if neither exists, the configuration file is assumed to be JSON.
+pub fn locate_config() -> Result<(ConfigFormat, CString16), Status> {
+    if let Some(config_path) = &LOAD_OPTIONS.config_path {
+        return Ok((ConfigFormat::from_path(&config_path.to_string()), config_path.clone()));
+    }
+
+    let mut efifs = match uefi::boot::get_image_file_system(image_handle!()) {
+        Ok(ok) => FileSystem::new(ok),
+        Err(err) => {
+            eprintln!("Unable to read image file system");
+            return Err(err.status());
+        }
+    };
+
+    let json_exists = efifs.try_exists(ConfigFormat::JSON.path()).unwrap_or(false);
+    let toml_exists = efifs.try_exists(ConfigFormat::TOML.path()).unwrap_or(false);
+    match (json_exists, toml_exists) {
+        (true, true) => {
+            eprintln!(
+                "Both {} and {} exist, remove one of them so it is clear which config to use",
+                ConfigFormat::JSON.path(), ConfigFormat::TOML.path()
+            );
+            Err(Status::ABORTED)
+        }
+        (false, true) => Ok((ConfigFormat::TOML, ConfigFormat::TOML.path().into())),
+        _ => Ok((ConfigFormat::JSON, ConfigFormat::JSON.path().into()))
+    }
+}
+
+/// Attempts to read a configuration file on the bootloader's file system.
+fn read_config_file(path: &CStr16) -> Result<Vec<u8>, Status> {
     // Attempt to get the file system containing the bootloader - the config file should be in the same file system
     let mut efifs = match uefi::boot::get_image_file_system(image_handle!()) {
         Ok(ok) => FileSystem::new(ok),
@@ -29,24 +267,57 @@ pub fn read_config() -> Result<Vec<u8>, Status> {
     };
 
     // Check if the config file exists - this ONLY checks if a directory entry exists at the path
-    if !efifs.try_exists(CONFIG_PATH).unwrap() {
-        eprintln!("No config file found");
+    if !efifs.try_exists(path).unwrap() {
+        eprintln!("No config file found at {}", path);
         return Err(Status::ABORTED);
     }
 
     // Check if the entry at the config file's path is a directory and fail if so
-    let wtconfig_info = efifs.metadata(CONFIG_PATH).unwrap();
+    let wtconfig_info = efifs.metadata(path).unwrap();
     if wtconfig_info.is_directory() {
-        eprintln!("Directory found instead of wtconfig file");
+        eprintln!("Directory found instead of config file at {}", path);
         return Err(Status::ABORTED);
     }
 
     // Attempt to read the config file
-    match efifs.read(CONFIG_PATH) {
+    match efifs.read(path) {
         Ok(ok) => Ok(ok),
         Err(_) => {
-            eprintln!("Failed to read config");
+            eprintln!("Failed to read config at {}", path);
+            Err(Status::ABORTED)
+        }
+    }
+}
+
+/// Attempts to read a configuration file on another partition through its file system driver.
+fn read_config_location(location: &FileLocation) -> Result<Vec<u8>, Status> {
+    match location.read() {
+        Ok(ok) => Ok(ok),
+        Err(err) => {
+            eprintln!("Failed to read config at {} on partition {} of disk {}: {:?}", location.path, location.partition, location.disk_guid, err);
             Err(Status::ABORTED)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Saves a successfully loaded configuration as the last-known-good backup of its format, if it differs from the current backup.
+fn backup_config(format: ConfigFormat, buffer: &[u8]) {
+    let mut efifs = match uefi::boot::get_image_file_system(image_handle!()) {
+        Ok(ok) => FileSystem::new(ok),
+        Err(_) => return
+    };
+
+    // Avoid needlessly writing to the ESP
+    if efifs.read(format.backup_path()).is_ok_and(|t| t == buffer) {
+        return;
+    }
+
+    match efifs.write(format.backup_path(), buffer) {
+        Ok(_) => {
+            dprintln!("Updated last-known-good config backup");
+        }
+        Err(_) => {
+            wprintln!("Unable to back up config");
+        }
+    }
+}
@@ -1,9 +1,17 @@
+mod autodetect;
+mod bls;
+pub mod efivar;
+pub mod integrity;
 pub mod load;
 mod parse;
+pub mod serialize;
+mod toml;
+pub mod vars;
 pub mod write;
 
 extern crate alloc;
 
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 use core::fmt::Display;
@@ -12,14 +20,35 @@ use core::str::FromStr;
 use spin::RwLock;
 use uefi::{cstr16, CStr16, Guid};
 
+use crate::{boot, dev};
+use crate::boot::BootFailure;
+use crate::dev::cache::DEFAULT_BLOCK_CACHE_SIZE;
+use crate::wtcore::config::serialize::OwnedJSONValue;
+use crate::wtcore::password::PasswordHash;
+
 /// Path to the bootloader configuration file.
 const CONFIG_PATH: &CStr16 = cstr16!("\\EFI\\wakatiwai\\wtconfig.json");
+/// Path to the alternative TOML bootloader configuration file.
+const CONFIG_TOML_PATH: &CStr16 = cstr16!("\\EFI\\wakatiwai\\wtconfig.toml");
+/// Path to the last-known-good copy of the bootloader configuration file.
+const CONFIG_BACKUP_PATH: &CStr16 = cstr16!("\\EFI\\wakatiwai\\wtconfig.json.bak");
+/// Path to the last-known-good copy of the alternative TOML bootloader configuration file.
+const CONFIG_TOML_BACKUP_PATH: &CStr16 = cstr16!("\\EFI\\wakatiwai\\wtconfig.toml.bak");
+/// Suffix of the file next to the configuration file which new bootloader configurations are written to before replacing it.
+const CONFIG_TEMP_SUFFIX: &str = ".tmp";
+
+/// The schema version of the bootloader configuration file understood by this bootloader.
+/// Configuration files without a version predate versioning, but share the layout of version 1.
+pub const CONFIG_VERSION: i32 = 1;
 
 /// The loaded configuration for the bootloader.
 pub static CONFIG: RwLock<Config> = RwLock::new(Config::new());
+/// The loaded configuration as written in the bootloader configuration file, before load options and other overrides are applied
+/// and without boot entries read from elsewhere. This is the configuration `save_config` writes back.
+pub static WRITTEN_CONFIG: RwLock<Config> = RwLock::new(Config::new());
 
 /// Configuration variables for the bootloader.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Config {
     /// The log level to be used. This value determines which kinds of messages can be printed to the screen.
     pub log_level: LogLevel,
@@ -33,11 +62,31 @@ pub struct Config {
     pub edit_config: bool,
     /// Determines if the screen should be cleared before the boot option menu is drawn.
     pub menu_clear: bool,
-    /// An array describing all the boot entries in the bootloader configuration file.
-    pub boot_entries: Vec<BootEntry>,
+    /// Determines if the boot option menu is shown, or only shown on request.
+    pub menu_mode: MenuMode,
+    /// Determines if a copy of the configuration should be kept in an EFI variable, to be loaded if the configuration file cannot be.
+    pub nv_config: bool,
+    /// The hash of the password required to edit the configuration, exit, escape to firmware, or boot locked boot options.
+    pub password: Option<PasswordHash>,
+    /// Determines if Boot Loader Specification entries should be read from readable partitions.
+    pub bls: bool,
+    /// The maximum number of disk blocks kept in the block cache.
+    pub disk_cache: usize,
+    /// Determines which programs on the ESP should have boot entries generated for them.
+    pub autodetect: Autodetect,
+    /// User-defined variables which may be referenced in boot entries.
+    pub variables: BTreeMap<String, String>,
+    /// The files on other partitions containing additional boot entries.
+    pub includes: Vec<FileLocation>,
+    /// The templates boot entries may inherit their properties from, as they appear in the bootloader configuration file.
+    pub templates: Vec<OwnedJSONValue>,
+    /// An array describing all the top-level boot entries and groups, in the order they appear in the bootloader configuration file.
+    pub boot_entries: Vec<BootItem>,
 }
 
 impl Config {
+    #[doc(hidden)]
+    const KEY_VERSION: &'static str = "version";
     #[doc(hidden)]
     const KEY_LOG_LEVEL: &'static str = "loglevel";
     #[doc(hidden)]
@@ -51,8 +100,28 @@ impl Config {
     #[doc(hidden)]
     const KEY_MENU_CLEAR: &'static str = "menuclear";
     #[doc(hidden)]
+    const KEY_MENU_MODE: &'static str = "menu";
+    #[doc(hidden)]
+    const KEY_NV_CONFIG: &'static str = "nvconfig";
+    #[doc(hidden)]
+    const KEY_PASSWORD: &'static str = "password";
+    #[doc(hidden)]
+    const KEY_BLS: &'static str = "bls";
+    #[doc(hidden)]
+    const KEY_DISK_CACHE: &'static str = "diskcache";
+    #[doc(hidden)]
+    const KEY_AUTODETECT: &'static str = "autodetect";
+    #[doc(hidden)]
+    const KEY_VARIABLES: &'static str = "variables";
+    #[doc(hidden)]
+    const KEY_TEMPLATES: &'static str = "templates";
+    #[doc(hidden)]
+    const KEY_INCLUDES: &'static str = "includes";
+    #[doc(hidden)]
     const KEY_BOOT_ENTRIES: &'static str = "bootentries";
 
+    #[doc(hidden)]
+    const DEFAULT_VERSION: i32 = 1;
     #[doc(hidden)]
     const DEFAULT_LOG_LEVEL: LogLevel = LogLevel::NORMAL;
     #[doc(hidden)]
@@ -65,6 +134,14 @@ impl Config {
     const DEFAULT_EDIT_CONFIG: bool = true;
     #[doc(hidden)]
     const DEFAULT_MENU_CLEAR: bool = true;
+    #[doc(hidden)]
+    const DEFAULT_MENU_MODE: MenuMode = MenuMode::VISIBLE;
+    #[doc(hidden)]
+    const DEFAULT_NV_CONFIG: bool = false;
+    #[doc(hidden)]
+    const DEFAULT_BLS: bool = false;
+    #[doc(hidden)]
+    const DEFAULT_DISK_CACHE: i32 = DEFAULT_BLOCK_CACHE_SIZE as i32;
 
     /// Returns a default (i.e. empty) configuration.
     pub const fn new() -> Self {
@@ -75,6 +152,15 @@ impl Config {
             firmware: Config::DEFAULT_FIRMWARE,
             edit_config: Config::DEFAULT_EDIT_CONFIG,
             menu_clear: Config::DEFAULT_MENU_CLEAR,
+            menu_mode: Config::DEFAULT_MENU_MODE,
+            nv_config: Config::DEFAULT_NV_CONFIG,
+            password: None,
+            bls: Config::DEFAULT_BLS,
+            disk_cache: Config::DEFAULT_DISK_CACHE as usize,
+            autodetect: Autodetect::new(),
+            variables: BTreeMap::new(),
+            includes: Vec::new(),
+            templates: Vec::new(),
             boot_entries: Vec::new(),
         }
     }
@@ -82,29 +168,92 @@ impl Config {
 
 impl Display for Config {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(
-            f,
-"{{
-    {log_level_key}: {log_level_val:?},
-    {timeout_key}: {timeout_val},
-    {exit_key}: {exit_val},
-    {firmware_key}: {firmware_val},
-    {edit_config_key}: {edit_config_val},
-    {menu_clear_key}: {menu_clear_val}
-}}",
-            log_level_key = Config::KEY_LOG_LEVEL,
-            log_level_val = self.log_level,
-            timeout_key = Config::KEY_TIMEOUT,
-            timeout_val = self.timeout,
-            exit_key = Config::KEY_EXIT,
-            exit_val = self.exit,
-            firmware_key = Config::KEY_FIRMWARE,
-            firmware_val = self.firmware,
-            edit_config_key = Config::KEY_EDIT_CONFIG,
-            edit_config_val = self.edit_config,
-            menu_clear_key = Config::KEY_MENU_CLEAR,
-            menu_clear_val = self.menu_clear
-        )
+        write!(f, "{}", self.to_json())
+    }
+}
+
+/// Describes which programs on the ESP should have boot entries generated for them.
+#[derive(Clone, Debug)]
+pub struct Autodetect {
+    /// Determines if Unified Kernel Images in `\EFI\Linux` should be detected.
+    pub uki: bool,
+    /// Determines if the Windows Boot Manager should be detected.
+    pub windows: bool,
+    /// Determines if other EFI programs in `\EFI\*` should be detected.
+    pub efi: bool,
+}
+
+impl Autodetect {
+    #[doc(hidden)]
+    const KEY_UKI: &'static str = "uki";
+    #[doc(hidden)]
+    const KEY_WINDOWS: &'static str = "windows";
+    #[doc(hidden)]
+    const KEY_EFI: &'static str = "efi";
+
+    #[doc(hidden)]
+    const DEFAULT_UKI: bool = true;
+    #[doc(hidden)]
+    const DEFAULT_WINDOWS: bool = true;
+    #[doc(hidden)]
+    const DEFAULT_EFI: bool = true;
+
+    /// Returns a disabled autodetection configuration.
+    pub const fn new() -> Self {
+        Autodetect {
+            uki: false,
+            windows: false,
+            efi: false,
+        }
+    }
+
+    /// Checks if any kind of program should be detected.
+    pub fn is_enabled(&self) -> bool {
+        self.uki || self.windows || self.efi
+    }
+}
+
+/// The formats the bootloader configuration file may be written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+    JSON,
+    TOML,
+}
+
+impl ConfigFormat {
+    /// Determines the format of a configuration file from its extension.
+    pub fn from_path(path: &str) -> Self {
+        if path.to_lowercase().ends_with(".toml") {
+            ConfigFormat::TOML
+        }
+        else {
+            ConfigFormat::JSON
+        }
+    }
+
+    /// Determines the format of a configuration from its contents.
+    /// A JSON configuration is an object, so it starts with `{`, which cannot start a TOML document.
+    pub fn detect(buffer: &[u8]) -> Self {
+        match buffer.iter().find(|t| !t.is_ascii_whitespace()) {
+            Some(b'{') => ConfigFormat::JSON,
+            _ => ConfigFormat::TOML
+        }
+    }
+
+    /// Returns the path of the bootloader configuration file of this format.
+    pub const fn path(&self) -> &'static CStr16 {
+        match self {
+            ConfigFormat::JSON => CONFIG_PATH,
+            ConfigFormat::TOML => CONFIG_TOML_PATH,
+        }
+    }
+
+    /// Returns the path of the last-known-good copy of the bootloader configuration file of this format.
+    pub const fn backup_path(&self) -> &'static CStr16 {
+        match self {
+            ConfigFormat::JSON => CONFIG_BACKUP_PATH,
+            ConfigFormat::TOML => CONFIG_TOML_BACKUP_PATH,
+        }
     }
 }
 
@@ -136,39 +285,244 @@ impl FromStr for LogLevel {
     }
 }
 
+/// The ways in which the boot option menu can be presented.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum MenuMode {
+    // The menu is always shown
+    #[default]
+    VISIBLE,
+    // The menu is only shown if a key is pressed before the timeout elapses
+    HIDDEN,
+}
+
+impl MenuMode {
+    /// Returns the name of the menu mode as used in the bootloader configuration file.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MenuMode::VISIBLE => "visible",
+            MenuMode::HIDDEN => "hidden",
+        }
+    }
+}
+
+impl FromStr for MenuMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "visible" => Ok(MenuMode::VISIBLE),
+            "hidden" => Ok(MenuMode::HIDDEN),
+            _ => Ok(MenuMode::default()),
+        }
+    }
+}
+
+/// Describes an element of a `bootentries` array, which is either a boot entry or a group.
+#[derive(Clone, Debug)]
+pub enum BootItem {
+    /// A boot entry.
+    Entry(BootEntry),
+    /// A group of boot entries, displayed as a submenu.
+    Group(BootGroup),
+}
+
+impl BootItem {
+    /// Returns a copy of this boot entry or group, with all boot entries resolved as in `BootEntry::resolved`.
+    pub fn resolved(&self) -> BootItem {
+        match self {
+            BootItem::Entry(entry) => BootItem::Entry(entry.resolved()),
+            BootItem::Group(group) => BootItem::Group(BootGroup {
+                name: group.name.clone(),
+                boot_entries: group.boot_entries.iter().map(BootItem::resolved).collect()
+            })
+        }
+    }
+}
+
+/// Describes a group of boot options, displayed as a submenu.
+#[derive(Clone, Debug, Default)]
+pub struct BootGroup {
+    /// The name of the group, displayed to the user.
+    pub name: String,
+    /// An array describing the boot entries and groups nested in this group, in the order they appear.
+    pub boot_entries: Vec<BootItem>,
+}
+
+impl BootGroup {
+    #[doc(hidden)]
+    const KEY_GROUP: &'static str = "group";
+}
+
+/// Describes the conditions under which a boot option is offered.
+/// Conditions which are `None` always hold.
+#[derive(Clone, Debug, Default)]
+pub struct BootCondition {
+    /// Requires Secure Boot to be enabled (or disabled).
+    pub secure_boot: Option<bool>,
+    /// Requires the SMBIOS system manufacturer to match a glob pattern.
+    pub smbios_manufacturer: Option<String>,
+    /// Requires the SMBIOS system product name to match a glob pattern.
+    pub smbios_product: Option<String>,
+    /// Requires a file to exist on the boot option's partition.
+    pub file_exists: Option<String>,
+    /// Requires a disk with the given GUID to be present.
+    pub disk_present: Option<Guid>,
+}
+
+impl BootCondition {
+    #[doc(hidden)]
+    const KEY_SECURE_BOOT: &'static str = "secureboot";
+    #[doc(hidden)]
+    const KEY_SMBIOS_MANUFACTURER: &'static str = "smbios.manufacturer";
+    #[doc(hidden)]
+    const KEY_SMBIOS_PRODUCT: &'static str = "smbios.product";
+    #[doc(hidden)]
+    const KEY_FILE_EXISTS: &'static str = "file_exists";
+    #[doc(hidden)]
+    const KEY_DISK_PRESENT: &'static str = "disk_present";
+}
+
+/// Describes the location of a file on any partition, addressed in the same way as a boot option.
+#[derive(Clone, Debug, Default)]
+pub struct FileLocation {
+    /// The GUID of the disk containing the file.
+    pub disk_guid: Guid,
+    /// The signature of the MBR disk containing the file, used instead of the disk GUID if non-zero.
+    pub disk_signature: u32,
+    /// The partition of the disk containing the file.
+    pub partition: u8,
+    /// The type of file system upon which the file resides.
+    pub fstype: String,
+    /// The path of the file.
+    pub path: String,
+}
+
+impl FileLocation {
+    /// Reads the file through the file system driver of its partition.
+    ///
+    /// A location which gives neither a disk GUID nor a disk signature is on the bootloader's disk.
+    pub fn read(&self) -> Result<Vec<u8>, BootFailure> {
+        let disk_guid = if self.disk_guid == Guid::ZERO && self.disk_signature == 0 {
+            *dev::BOOTLOADER_DISK_GUID
+        }
+        else {
+            self.disk_guid
+        };
+
+        boot::read_file(
+            &BootEntry {
+                disk_guid,
+                disk_signature: self.disk_signature,
+                partition: self.partition,
+                fstype: self.fstype.clone(),
+                ..Default::default()
+            },
+            &self.path
+        )
+    }
+}
+
+/// Describes an initial ramdisk (or microcode) image of a boot option.
+#[derive(Clone, Debug)]
+pub enum Initrd {
+    /// An image on the boot option's partition, given by its path.
+    Path(String),
+    /// An image on another partition.
+    Location(FileLocation),
+}
+
+impl Initrd {
+    /// Reads the image, through the file system driver of the given boot option if it is on the boot option's partition.
+    pub fn read(&self, entry: &BootEntry) -> Result<Vec<u8>, BootFailure> {
+        match self {
+            Initrd::Path(path) => boot::read_file(entry, path),
+            Initrd::Location(location) => location.read()
+        }
+    }
+
+    /// Returns the path of the image on its partition.
+    pub fn path(&self) -> &str {
+        match self {
+            Initrd::Path(path) => path,
+            Initrd::Location(location) => &location.path
+        }
+    }
+}
+
 /// Describes the properties of a boot option.
 #[derive(Clone, Debug, Default)]
 pub struct BootEntry {
     /// The name of the boot option, displayed to the user.
     pub name: String,
+    /// The name of the template this boot option inherits its properties from.
+    pub extends: String,
     /// Is this boot option on a removable drive?
     pub removable: bool,
+    /// Is this boot option only displayed once the user reveals hidden entries?
+    pub hidden: bool,
+    /// Does this boot option require the password to be booted?
+    pub locked: bool,
+    /// The conditions under which this boot option is offered.
+    pub condition: BootCondition,
     /// The GUID of the disk containing this boot option.
     pub disk_guid: Guid,
-    /// The partition of the disk containing this boot option.
+    /// The signature of the MBR disk containing this boot option, used instead of the disk GUID if non-zero.
+    pub disk_signature: u32,
+    /// The (1-based) number of the partition containing this boot option, or 0 if it is only selected by the selectors below.
     pub partition: u8,
+    /// The GUID of the partition containing this boot option, which selects it on any disk if no disk is given.
+    pub part_uuid: Guid,
+    /// The GPT name of the partition containing this boot option.
+    pub part_label: String,
+    /// The GPT type GUID of the partition containing this boot option.
+    pub part_type: Guid,
+    /// The UUID of the file system containing this boot option, as reported by its file system driver. Selects the partition on any disk.
+    pub fs_uuid: String,
+    /// The label of the file system containing this boot option, as reported by its file system driver. Selects the partition on any disk.
+    pub fs_label: String,
     /// The type of file system upon which this boot option resides.
     pub fstype: String,
     /// The type of program this boot option points to.
     pub ostype: String,
     /// The path of the boot option to be run.
     pub path: String,
-    /// The path of the inital ramdisk to use.
-    pub initrd: String,
+    /// The initial ramdisk images to use, concatenated in order.
+    pub initrd: Vec<Initrd>,
     /// The arguments passed to the boot option.
-    pub args: String
+    pub args: String,
+    /// Was this boot option generated, rather than read from the bootloader configuration file?
+    pub generated: bool
 }
 
 impl BootEntry {
     #[doc(hidden)]
     const KEY_NAME: &'static str = "name";
     #[doc(hidden)]
+    const KEY_EXTENDS: &'static str = "extends";
+    #[doc(hidden)]
     const KEY_REMOVABLE: &'static str = "removable";
     #[doc(hidden)]
+    const KEY_HIDDEN: &'static str = "hidden";
+    #[doc(hidden)]
+    const KEY_LOCKED: &'static str = "locked";
+    #[doc(hidden)]
+    const KEY_CONDITION: &'static str = "if";
+    #[doc(hidden)]
     const KEY_DISK: &'static str = "diskguid";
     #[doc(hidden)]
+    const KEY_DISK_SIGNATURE: &'static str = "disksignature";
+    #[doc(hidden)]
     const KEY_PARTITION: &'static str = "partition";
     #[doc(hidden)]
+    const KEY_PART_UUID: &'static str = "partuuid";
+    #[doc(hidden)]
+    const KEY_PART_LABEL: &'static str = "partlabel";
+    #[doc(hidden)]
+    const KEY_PART_TYPE: &'static str = "parttype";
+    #[doc(hidden)]
+    const KEY_FS_UUID: &'static str = "fsuuid";
+    #[doc(hidden)]
+    const KEY_FS_LABEL: &'static str = "fslabel";
+    #[doc(hidden)]
     const KEY_FS: &'static str = "fstype";
     #[doc(hidden)]
     const KEY_PROGTYPE: &'static str = "ostype";
@@ -181,32 +535,45 @@ impl BootEntry {
 
     /// The maximum name length for a boot entry.
     pub const MAX_NAME_LENGTH: usize = 64;
+
+    /// Shortens a name to the maximum name length, counted in characters so that none is split.
+    pub fn truncate_name(name: &str) -> String {
+        name.chars().take(BootEntry::MAX_NAME_LENGTH).collect()
+    }
+
+    /// Returns a copy of this boot entry as it is booted, on the bootloader's disk if it gives no other way to find its partition.
+    pub fn resolved(&self) -> BootEntry {
+        let has_volume = !self.fs_uuid.is_empty() || !self.fs_label.is_empty();
+        // Partition GUIDs are unique, so a partition given by its GUID (or by its file system) is searched for on every disk instead
+        if self.disk_guid == Guid::ZERO && self.disk_signature == 0 && self.part_uuid == Guid::ZERO && !has_volume && !self.removable {
+            return BootEntry {
+                disk_guid: *dev::BOOTLOADER_DISK_GUID,
+                ..self.clone()
+            };
+        }
+
+        self.clone()
+    }
+
+    /// Returns a copy of this boot entry with all variables in its path, initrd, and args expanded.
+    pub fn expanded(&self, variables: &BTreeMap<String, String>) -> BootEntry {
+        BootEntry {
+            path: vars::expand_variables(&self.path, self, variables),
+            initrd: self.initrd.iter().map(|t| match t {
+                Initrd::Path(path) => Initrd::Path(vars::expand_variables(path, self, variables)),
+                Initrd::Location(location) => Initrd::Location(FileLocation {
+                    path: vars::expand_variables(&location.path, self, variables),
+                    ..location.clone()
+                })
+            }).collect(),
+            args: vars::expand_variables(&self.args, self, variables),
+            ..self.clone()
+        }
+    }
 }
 
 impl Display for BootEntry {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(
-            f,
-"{{
-    {name_key}: {name_val},
-    {removable_key}: {removable_val},
-    {disk_key}: {disk_val}
-    {partition_key}: {partition_val}
-    {fs_key}: {fs_val:?}
-    {progtype_key}: {progtype_val:?}
-    {path_key}: {path_val:?}
-    {initrd_key}: {initrd_val:?}
-    {args_key}: {args_val:?}
-}}",
-            name_key = BootEntry::KEY_NAME, name_val = self.name,
-            removable_key = BootEntry::KEY_REMOVABLE, removable_val = self.removable,
-            disk_key = BootEntry::KEY_DISK, disk_val = self.disk_guid,
-            partition_key = BootEntry::KEY_PARTITION, partition_val = self.partition,
-            fs_key = BootEntry::KEY_FS, fs_val = self.fstype,
-            progtype_key = BootEntry::KEY_PROGTYPE, progtype_val = self.ostype,
-            path_key = BootEntry::KEY_PATH, path_val = self.path,
-            initrd_key = BootEntry::KEY_INITRD, initrd_val = self.initrd,
-            args_key = BootEntry::KEY_ARGS, args_val = self.args
-        )
+        write!(f, "{}", self.to_json(&[]))
     }
 }
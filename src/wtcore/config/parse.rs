@@ -1,5 +1,6 @@
 extern crate alloc;
 
+use alloc::collections::BTreeMap;
 use alloc::string::ToString;
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -10,8 +11,15 @@ use microjson::*;
 use uefi::prelude::*;
 
 use crate::*;
+use crate::dev::cache::set_block_cache_size;
 use crate::wtcore::*;
 use crate::wtcore::config::*;
+use crate::wtcore::config::autodetect::detect_entries;
+use crate::wtcore::config::bls::read_bls_entries;
+use crate::wtcore::config::integrity::{editing_locked, verification_enabled};
+use crate::wtcore::config::serialize::OwnedJSONValue;
+use crate::wtcore::options::LOAD_OPTIONS;
+use crate::wtcore::password::PasswordHash;
 
 #[doc(hidden)]
 macro_rules! unwrap_json_var {
@@ -28,7 +36,13 @@ macro_rules! unwrap_json_var {
 /// Parses a buffer and sets the bootloader configuration accordingly.
 pub fn parse_config(buffer: Vec<u8>) -> Result<(), Status> {
     // Converts the buffer to a string and checks if it's valid JSON
-    let buffer_string = &String::from_utf8(buffer).unwrap();
+    let buffer_string = match String::from_utf8(buffer) {
+        Ok(ok) => ok,
+        Err(_) => {
+            eprintln!("Config file is not valid UTF-8");
+            return Err(Status::ABORTED);
+        }
+    };
     let json = match JSONValue::load_and_verify(&buffer_string) {
         Ok(ok) => ok,
         Err(err) => {
@@ -38,10 +52,22 @@ pub fn parse_config(buffer: Vec<u8>) -> Result<(), Status> {
     };
 
     // Write log level earlier so conditional prints work properly
-    let log_level = unwrap_json_var!(get_json_var::<LogLevel>(&json, Config::KEY_LOG_LEVEL, Config::DEFAULT_LOG_LEVEL, false, JSONValueType::String));
-    let mut config = CONFIG.write();
-    config.log_level = log_level.clone();
-    drop(config);
+    let log_level       = unwrap_json_var!(get_json_var::<LogLevel>(&json, Config::KEY_LOG_LEVEL, Config::DEFAULT_LOG_LEVEL, false, JSONValueType::String));
+    CONFIG.write().log_level = LOAD_OPTIONS.log_level.clone().unwrap_or(log_level.clone());
+
+    // Bring configs written for older bootloaders up to date before reading anything else
+    let version = unwrap_json_var!(get_json_var::<i32>(&json, Config::KEY_VERSION, Config::DEFAULT_VERSION, false, JSONValueType::Number));
+    if version < CONFIG_VERSION {
+        // The migrated config is not written back, as it may be verified or on another partition - so this happens on every load
+        dprintln!("Config uses schema version {}, migrating to version {}", version, CONFIG_VERSION);
+        return parse_config(migrate_config(json, version)?.into_bytes());
+    }
+    if version > CONFIG_VERSION {
+        wprintln!(
+            "Config uses schema version {}, which is newer than the supported version {} - unknown properties will be ignored",
+            version, CONFIG_VERSION
+        );
+    }
 
     // Get config properties
     let timeout         = unwrap_json_var!(get_json_var::<i32>(&json, Config::KEY_TIMEOUT, Config::DEFAULT_TIMEOUT, false, JSONValueType::Number));
@@ -49,98 +75,622 @@ pub fn parse_config(buffer: Vec<u8>) -> Result<(), Status> {
     let firmware        = unwrap_json_var!(get_json_var::<bool>(&json, Config::KEY_FIRMWARE, Config::DEFAULT_FIRMWARE, false, JSONValueType::Bool));
     let edit_config     = unwrap_json_var!(get_json_var::<bool>(&json, Config::KEY_EDIT_CONFIG, Config::DEFAULT_EDIT_CONFIG, false, JSONValueType::Bool));
     let menu_clear      = unwrap_json_var!(get_json_var::<bool>(&json, Config::KEY_MENU_CLEAR, Config::DEFAULT_MENU_CLEAR, false, JSONValueType::Bool));
-
-    // Get boot entries
-    let mut boot_entries: Vec<BootEntry> = Vec::new();
-    if match json.get_key_value(Config::KEY_BOOT_ENTRIES) {
-        Ok(type_value) => { type_value.value_type == JSONValueType::Array }
-        Err(_) => false
-    } {
-        let boot_entry_array = json.get_key_value(Config::KEY_BOOT_ENTRIES).unwrap().iter_array().unwrap();
-        for bootentry_json in boot_entry_array {
-            let bootentry = match parse_bootentry(bootentry_json) {
-                Ok(ok) => ok,
-                Err(err) => {
-                    eprintln!("Failed to parse boot entry: {}", err);
+    let menu_mode       = unwrap_json_var!(get_json_var::<MenuMode>(&json, Config::KEY_MENU_MODE, Config::DEFAULT_MENU_MODE, false, JSONValueType::String));
+    let nv_config       = unwrap_json_var!(get_json_var::<bool>(&json, Config::KEY_NV_CONFIG, Config::DEFAULT_NV_CONFIG, false, JSONValueType::Bool));
+    let password        = match json.get_key_value(Config::KEY_PASSWORD) {
+        Ok(password_json) if password_json.value_type == JSONValueType::String => {
+            match PasswordHash::parse(password_json.read_string().unwrap()) {
+                Some(some) => Some(some),
+                None => {
+                    eprintln!("Key \"{}\" must be a password hash of the form \"pbkdf2-sha256$<iterations>$<salt>$<hash>\"", Config::KEY_PASSWORD);
                     return Err(Status::ABORTED);
                 }
-            };
+            }
+        }
+        Ok(_) => {
+            eprintln!("Key \"{}\" must be a string", Config::KEY_PASSWORD);
+            return Err(Status::ABORTED);
+        }
+        Err(_) => None
+    };
+    let bls             = unwrap_json_var!(get_json_var::<bool>(&json, Config::KEY_BLS, Config::DEFAULT_BLS, false, JSONValueType::Bool));
+    let disk_cache      = unwrap_json_var!(get_json_var::<i32>(&json, Config::KEY_DISK_CACHE, Config::DEFAULT_DISK_CACHE, false, JSONValueType::Number));
+    if disk_cache < 0 {
+        eprintln!("Key \"{}\" must not be negative", Config::KEY_DISK_CACHE);
+        return Err(Status::ABORTED);
+    }
+    let disk_cache = disk_cache as usize;
+    // Includes and BLS entries are read from disk below, so resize the cache first
+    set_block_cache_size(disk_cache);
+    let autodetect      = match json.get_key_value(Config::KEY_AUTODETECT) {
+        Ok(autodetect_json) if autodetect_json.value_type == JSONValueType::Object => {
+            parse_autodetect(autodetect_json)?
+        }
+        // Autodetection is opt-in
+        _ => Autodetect::new()
+    };
+    let variables       = match json.get_key_value(Config::KEY_VARIABLES) {
+        Ok(variables_json) if variables_json.value_type == JSONValueType::Object => {
+            parse_variables(variables_json)?
+        }
+        _ => BTreeMap::new()
+    };
 
-            dprintln!("Detected boot entry: {}", bootentry);
-            boot_entries.push(bootentry);
+    // Get templates, these must all be known before any boot entry can be resolved
+    let (templates, owned_templates) = match json.get_key_value(Config::KEY_TEMPLATES) {
+        Ok(templates_json) if templates_json.value_type == JSONValueType::Array => {
+            // Templates are also kept as written, so the config can be serialised again
+            let mut owned_templates = Vec::new();
+            for template_json in templates_json.iter_array().unwrap() {
+                owned_templates.push(OwnedJSONValue::from_json(&template_json)?);
+            }
+            (collect_templates(templates_json)?, owned_templates)
         }
+        _ => (BTreeMap::new(), Vec::new())
+    };
+
+    // Get boot entries and groups
+    let written_boot_entries = if match json.get_key_value(Config::KEY_BOOT_ENTRIES) {
+        Ok(type_value) => { type_value.value_type == JSONValueType::Array }
+        Err(_) => false
+    } {
+        parse_bootentries(json.get_key_value(Config::KEY_BOOT_ENTRIES).unwrap(), &templates)?
     } else {
         wprintln!("No boot entries detected in config");
+        Vec::new()
+    };
+    let mut boot_entries: Vec<BootItem> = written_boot_entries.iter().map(BootItem::resolved).collect();
+
+    // Get drop-in boot entries from other partitions, which go after the configured ones
+    let includes = match json.get_key_value(Config::KEY_INCLUDES) {
+        Ok(includes_json) if includes_json.value_type == JSONValueType::Array => {
+            let mut includes = Vec::new();
+            for include_json in includes_json.iter_array().unwrap() {
+                includes.push(parse_file_location(include_json)?);
+            }
+            includes
+        }
+        _ => Vec::new()
+    };
+    // Entries outside the config file are not covered by its hash, so they cannot be trusted when it is verified
+    let verification_enabled = verification_enabled();
+    if verification_enabled && (!includes.is_empty() || bls) {
+        wprintln!("Ignoring drop-in and BLS entries as they cannot be verified");
+    }
+    if !verification_enabled {
+        for include in includes.iter() {
+            boot_entries.extend(read_include(include, &templates)?.into_iter().map(|t| BootItem::Entry(t.resolved())));
+        }
+    }
+
+    // Get Boot Loader Specification entries, which go after the configured ones
+    if bls && !verification_enabled {
+        boot_entries.extend(read_bls_entries().into_iter().map(BootItem::Entry));
+    }
+
+    // Get generated entries, which go after all others
+    if autodetect.is_enabled() {
+        boot_entries.extend(detect_entries(&autodetect).into_iter().map(BootItem::Entry));
     }
 
-    // Open writable lock on the config
-    let mut config = CONFIG.write();
-    *config = Config {
+    let written_config = Config {
         log_level,
         timeout,
         exit,
         firmware,
         edit_config,
         menu_clear,
-        boot_entries
+        menu_mode,
+        nv_config,
+        password,
+        bls,
+        disk_cache,
+        autodetect,
+        variables,
+        includes,
+        templates: owned_templates,
+        boot_entries: written_boot_entries
     };
-    if config.boot_entries.len() == 0 {
+
+    // The config as written is kept apart from the overrides below, so that it can be saved without them
+    let mut config = Config {
+        boot_entries,
+        ..written_config.clone()
+    };
+    if let Some(some) = &LOAD_OPTIONS.log_level {
+        config.log_level = some.clone();
+    }
+    if let Some(some) = LOAD_OPTIONS.timeout {
+        config.timeout = some;
+    }
+    // A verified config must not be changed from the bootloader, as the change could not be verified
+    let editing_locked = editing_locked();
+    if config.edit_config && editing_locked {
+        wprintln!("Config editing is disabled while the config is verified");
+        config.edit_config = false;
+    }
+    // File system drivers are read-only, so a config on another partition cannot be written
+    if config.edit_config && LOAD_OPTIONS.config_location.is_some() {
+        wprintln!("Config editing is disabled for a config on another partition");
+        config.edit_config = false;
+    }
+    if config.boot_entries.is_empty() {
         // If no boot entries are available, offer exit, config edit, and wait for user input
-        if config.log_level != LogLevel::SILENT {
-            // wprintln! won't work here because it relies on a readable config lock, which will be blocked by the above writable lock
-            wprintln_force!("No boot entries provided, enabling exit, config editor, and halting for user input...");
-        }
+        wprintln!("No boot entries provided, enabling exit, config editor, and halting for user input...");
         config.exit = true;
-        config.edit_config = true;
+        config.edit_config = !editing_locked && LOAD_OPTIONS.config_location.is_none();
         config.timeout = -1;
     }
-    drop(config);
+
+    *WRITTEN_CONFIG.write() = written_config;
+    *CONFIG.write() = config;
 
     Ok(())
 }
 
+/// Converts a config of an older schema version to the current schema version.
+fn migrate_config(json: JSONValue, version: i32) -> Result<String, Status> {
+    // Each migration converts a config from the version after its index to the next version, e.g. the first converts version 1 to version 2.
+    // No version has changed the layout of the config yet
+    const MIGRATIONS: [fn(&mut OwnedJSONValue); (CONFIG_VERSION - 1) as usize] = [];
+
+    let mut config = OwnedJSONValue::from_json(&json)?;
+    for migration in MIGRATIONS.iter().skip((version.max(1) - 1) as usize) {
+        migration(&mut config);
+    }
+    config.insert(Config::KEY_VERSION, OwnedJSONValue::Number(CONFIG_VERSION as i64));
+
+    Ok(config.to_string())
+}
+
+/// Parses a JSON object and attempts to return a corresponding `Autodetect`.
+fn parse_autodetect(json: JSONValue) -> Result<Autodetect, Status> {
+    let uki             = unwrap_json_var!(get_json_var::<bool>(&json, Autodetect::KEY_UKI, Autodetect::DEFAULT_UKI, false, JSONValueType::Bool));
+    let windows         = unwrap_json_var!(get_json_var::<bool>(&json, Autodetect::KEY_WINDOWS, Autodetect::DEFAULT_WINDOWS, false, JSONValueType::Bool));
+    let efi             = unwrap_json_var!(get_json_var::<bool>(&json, Autodetect::KEY_EFI, Autodetect::DEFAULT_EFI, false, JSONValueType::Bool));
+
+    Ok(Autodetect {
+        uki,
+        windows,
+        efi
+    })
+}
+
+/// Parses a JSON object of strings and returns the variables it defines.
+fn parse_variables(json: JSONValue) -> Result<BTreeMap<String, String>, Status> {
+    let mut ret = BTreeMap::new();
+
+    let variable_iter = match json.iter_object() {
+        Ok(ok) => ok,
+        Err(err) => {
+            eprintln!("Failed to parse variables: {}", err);
+            return Err(Status::ABORTED);
+        }
+    };
+    for variable in variable_iter {
+        let (name, value) = match variable {
+            Ok(ok) => ok,
+            Err(err) => {
+                eprintln!("Failed to parse variable: {}", err);
+                return Err(Status::ABORTED);
+            }
+        };
+
+        if value.value_type != JSONValueType::String {
+            eprintln!("Variable \"{}\" is not a string", name);
+            return Err(Status::ABORTED);
+        }
+        if vars::BUILTINS.contains(&name) {
+            wprintln!("Variable \"{}\" is built-in and cannot be redefined, ignoring...", name);
+            continue;
+        }
+
+        dprintln!("Detected variable: {} = {}", name, value.read_string().unwrap());
+        ret.insert(name.to_string(), value.read_string().unwrap().to_string());
+    }
+
+    Ok(ret)
+}
+
+/// Collects the JSON objects of an array of templates by name.
+fn collect_templates<'a>(json: JSONValue<'a>) -> Result<BTreeMap<&'a str, JSONValue<'a>>, Status> {
+    let mut ret = BTreeMap::new();
+
+    for template_json in json.iter_array().unwrap() {
+        if template_json.value_type != JSONValueType::Object {
+            eprintln!("Non-object in templates");
+            return Err(Status::COMPROMISED_DATA);
+        }
+
+        let name = match template_json.get_key_value(BootEntry::KEY_NAME) {
+            Ok(name_json) if name_json.value_type == JSONValueType::String => name_json.read_string().unwrap(),
+            _ => {
+                eprintln!("Could not locate required string key \"{}\" in template", BootEntry::KEY_NAME);
+                return Err(Status::ABORTED);
+            }
+        };
+        if ret.insert(name, template_json).is_some() {
+            eprintln!("Duplicate template \"{}\"", name);
+            return Err(Status::ABORTED);
+        }
+
+        dprintln!("Detected template: {}", name);
+    }
+
+    Ok(ret)
+}
+
+/// Resolves the chain of templates a JSON object extends, starting with the object itself.
+fn resolve_template_chain<'a>(json: JSONValue<'a>, templates: &BTreeMap<&'a str, JSONValue<'a>>) -> Result<Vec<JSONValue<'a>>, Status> {
+    let mut ret = vec![json];
+    let mut visited: Vec<&str> = Vec::new();
+
+    let mut current = json;
+    while let Ok(extends_json) = current.get_key_value(BootEntry::KEY_EXTENDS) {
+        if extends_json.value_type != JSONValueType::String {
+            eprintln!("Key \"{}\" must be a string", BootEntry::KEY_EXTENDS);
+            return Err(Status::ABORTED);
+        }
+
+        let template_name = extends_json.read_string().unwrap();
+        if visited.contains(&template_name) {
+            eprintln!("Template \"{}\" extends itself", template_name);
+            return Err(Status::ABORTED);
+        }
+        visited.push(template_name);
+
+        current = match templates.get(template_name) {
+            Some(some) => *some,
+            None => {
+                eprintln!("No template named \"{}\"", template_name);
+                return Err(Status::ABORTED);
+            }
+        };
+        ret.push(current);
+    }
+
+    Ok(ret)
+}
+
+/// Gets a variable from the first JSON object in a template chain which defines it.
+fn get_inherited_json_var<T: Default + Debug + FromStr + 'static>(chain: &[JSONValue], key: &str, default: T, required: bool, json_type: JSONValueType) -> Result<T, Status> {
+    for json in chain.iter() {
+        if match json.get_key_value(key) {
+            Ok(type_value) => { type_value.value_type == json_type }
+            Err(_) => false
+        } {
+            return get_json_var::<T>(json, key, default, required, json_type);
+        }
+    }
+
+    // Nothing in the chain defines the key, so let the entry itself report it missing
+    get_json_var::<T>(&chain[0], key, default, required, json_type)
+}
+
+/// Parses a JSON array of boot entries and groups, keeping them in order.
+fn parse_bootentries(json: JSONValue, templates: &BTreeMap<&str, JSONValue>) -> Result<Vec<BootItem>, Status> {
+    let mut boot_entries: Vec<BootItem> = Vec::new();
+
+    for bootentry_json in json.iter_array().unwrap() {
+        // Groups are distinguished from boot entries by their group key
+        if bootentry_json.value_type == JSONValueType::Object && bootentry_json.get_key_value(BootGroup::KEY_GROUP).is_ok() {
+            let bootgroup = parse_bootgroup(bootentry_json, templates)?;
+            boot_entries.push(BootItem::Group(bootgroup));
+            continue;
+        }
+
+        let bootentry = match parse_bootentry(bootentry_json, templates) {
+            Ok(ok) => ok,
+            Err(err) => {
+                eprintln!("Failed to parse boot entry: {}", err);
+                return Err(Status::ABORTED);
+            }
+        };
+
+        dprintln!("Detected boot entry: {}", bootentry);
+        boot_entries.push(BootItem::Entry(bootentry));
+    }
+
+    Ok(boot_entries)
+}
+
+/// Parses a JSON object and attempts to return a corresponding `BootGroup`.
+fn parse_bootgroup(json: JSONValue, templates: &BTreeMap<&str, JSONValue>) -> Result<BootGroup, Status> {
+    let name            = BootEntry::truncate_name(&unwrap_json_var!(get_json_var::<String>(&json, BootGroup::KEY_GROUP, String::new(), true, JSONValueType::String)));
+    dprintln!("Detected boot group: {}", name);
+
+    let boot_entries = match json.get_key_value(Config::KEY_BOOT_ENTRIES) {
+        Ok(bootentries_json) if bootentries_json.value_type == JSONValueType::Array => {
+            parse_bootentries(bootentries_json, templates)?
+        }
+        _ => {
+            wprintln!("No boot entries detected in group \"{}\"", name);
+            Vec::new()
+        }
+    };
+
+    Ok(BootGroup {
+        name,
+        boot_entries
+    })
+}
+
 /// Parses a JSON object and attempts to return a corresponding `BootEntry`.
-fn parse_bootentry(json: JSONValue) -> Result<BootEntry, Status> {
+fn parse_bootentry(json: JSONValue, templates: &BTreeMap<&str, JSONValue>) -> Result<BootEntry, Status> {
     // Check if the JSON given is indeed an object
     if json.value_type != JSONValueType::Object {
         eprintln!("Non-object in boot entries");
         return Err(Status::COMPROMISED_DATA);
     }
 
+    // Properties other than the name and path may be inherited from templates
+    let chain = resolve_template_chain(json, templates)?;
+
     // Get boot entry properties
     let name            = unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_NAME, String::new(), true, JSONValueType::String));
-    let removable       = unwrap_json_var!(get_json_var::<bool>(&json, BootEntry::KEY_REMOVABLE, false, false, JSONValueType::Bool));
-    let mut disk_guid   = unwrap_json_var!(get_json_var::<Guid>(&json, BootEntry::KEY_DISK, Guid::ZERO, false, JSONValueType::String));
-    let partition       = unwrap_json_var!(get_json_var::<u8>(&json, BootEntry::KEY_PARTITION, 0, true, JSONValueType::Number));
-    let fstype          = unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_FS, String::new(), true, JSONValueType::String));
-    let ostype          = unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_PROGTYPE, String::new(), true, JSONValueType::String));
+    let extends         = unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_EXTENDS, String::new(), false, JSONValueType::String));
+    let removable       = unwrap_json_var!(get_inherited_json_var::<bool>(&chain, BootEntry::KEY_REMOVABLE, false, false, JSONValueType::Bool));
+    let hidden          = unwrap_json_var!(get_inherited_json_var::<bool>(&chain, BootEntry::KEY_HIDDEN, false, false, JSONValueType::Bool));
+    let locked          = unwrap_json_var!(get_inherited_json_var::<bool>(&chain, BootEntry::KEY_LOCKED, false, false, JSONValueType::Bool));
+    let condition       = match chain.iter().find_map(|t| t.get_key_value(BootEntry::KEY_CONDITION).ok()) {
+        Some(condition_json) if condition_json.value_type == JSONValueType::Object => {
+            parse_condition(condition_json)?
+        }
+        Some(_) => {
+            eprintln!("Key \"{}\" must be an object", BootEntry::KEY_CONDITION);
+            return Err(Status::ABORTED);
+        }
+        None => BootCondition::default()
+    };
+    let mut disk_guid   = unwrap_json_var!(get_inherited_json_var::<Guid>(&chain, BootEntry::KEY_DISK, Guid::ZERO, false, JSONValueType::String));
+    let disk_signature  = parse_disk_signature(&unwrap_json_var!(get_inherited_json_var::<String>(&chain, BootEntry::KEY_DISK_SIGNATURE, String::new(), false, JSONValueType::String)))?;
+    let partition       = unwrap_json_var!(get_inherited_json_var::<u8>(&chain, BootEntry::KEY_PARTITION, 0, false, JSONValueType::Number));
+    let part_uuid       = unwrap_json_var!(get_inherited_json_var::<Guid>(&chain, BootEntry::KEY_PART_UUID, Guid::ZERO, false, JSONValueType::String));
+    let part_label      = unwrap_json_var!(get_inherited_json_var::<String>(&chain, BootEntry::KEY_PART_LABEL, String::new(), false, JSONValueType::String));
+    let part_type       = unwrap_json_var!(get_inherited_json_var::<Guid>(&chain, BootEntry::KEY_PART_TYPE, Guid::ZERO, false, JSONValueType::String));
+    let fs_uuid         = unwrap_json_var!(get_inherited_json_var::<String>(&chain, BootEntry::KEY_FS_UUID, String::new(), false, JSONValueType::String));
+    let fs_label        = unwrap_json_var!(get_inherited_json_var::<String>(&chain, BootEntry::KEY_FS_LABEL, String::new(), false, JSONValueType::String));
+    let fstype          = unwrap_json_var!(get_inherited_json_var::<String>(&chain, BootEntry::KEY_FS, String::new(), true, JSONValueType::String));
+    let ostype          = unwrap_json_var!(get_inherited_json_var::<String>(&chain, BootEntry::KEY_PROGTYPE, String::new(), true, JSONValueType::String));
     let path            = unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_PATH, String::new(), true, JSONValueType::String));
-    let initrd          = unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_INITRD, String::new(), false, JSONValueType::String));
-    let args            = unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_ARGS, String::new(), false, JSONValueType::String));
+    let initrd          = match chain.iter().find_map(|t| t.get_key_value(BootEntry::KEY_INITRD).ok()) {
+        Some(initrd_json) => parse_initrd(initrd_json)?,
+        None => Vec::new()
+    };
+    let args            = unwrap_json_var!(get_inherited_json_var::<String>(&chain, BootEntry::KEY_ARGS, String::new(), false, JSONValueType::String));
+
+    // The partition may be given by its number, selected by its GPT properties, or found by its file system
+    let has_selector = part_uuid != Guid::ZERO || !part_label.is_empty() || part_type != Guid::ZERO;
+    let has_volume = !fs_uuid.is_empty() || !fs_label.is_empty();
+    if partition == 0 && !has_selector && !has_volume {
+        eprintln!(
+            "Boot entry \"{}\" must give its partition through \"{}\", \"{}\", \"{}\", \"{}\", \"{}\", or \"{}\"",
+            name, BootEntry::KEY_PARTITION, BootEntry::KEY_PART_UUID, BootEntry::KEY_PART_LABEL, BootEntry::KEY_PART_TYPE,
+            BootEntry::KEY_FS_UUID, BootEntry::KEY_FS_LABEL
+        );
+        return Err(Status::ABORTED);
+    }
+    if has_volume && (disk_guid != Guid::ZERO || disk_signature != 0 || partition != 0 || has_selector) {
+        wprintln!("Disk and partition properties of boot entry \"{}\" are ignored, as its file system is searched for on every disk", name);
+    }
+    if disk_signature != 0 && has_selector && !has_volume {
+        // MBR disks have no GPT, so their partitions can only be given by number
+        if partition == 0 {
+            eprintln!(
+                "Boot entry \"{}\" must give its partition through \"{}\", as \"{}\", \"{}\" and \"{}\" cannot select partitions on MBR disks",
+                name, BootEntry::KEY_PARTITION, BootEntry::KEY_PART_UUID, BootEntry::KEY_PART_LABEL, BootEntry::KEY_PART_TYPE
+            );
+            return Err(Status::ABORTED);
+        }
+        wprintln!("Partition selectors of boot entry \"{}\" are ignored, as MBR disks have no GPT", name);
+    }
 
-    if disk_guid == Guid::ZERO {
+    if disk_signature != 0 {
+        // MBR disks have no GUID
+        disk_guid = Guid::ZERO;
+    }
+    // Partition GUIDs are unique, so a partition given by its GUID (or by its file system) is searched for on every disk instead
+    else if disk_guid == Guid::ZERO && part_uuid == Guid::ZERO && !has_volume {
         if removable {
             wprintln!("Removable disk specifies no disk GUID, ignoring...");
         }
         else {
+            // The disk is filled in by `BootEntry::resolved`, so that the config can be saved as written
             wprintln!("Disk property missing or malformed, assuming current...");
-            disk_guid = *dev::BOOTLOADER_DISK_GUID;
         }
     }
 
     Ok(BootEntry {
         name,
+        extends,
         removable,
+        hidden,
+        locked,
+        condition,
         disk_guid,
+        disk_signature,
         partition,
+        part_uuid,
+        part_label,
+        part_type,
+        fs_uuid,
+        fs_label,
         fstype,
         ostype,
         path,
         initrd,
-        args
+        args,
+        generated: false
+    })
+}
+
+/// Parses a JSON object and attempts to return a corresponding `FileLocation`.
+fn parse_file_location(json: JSONValue) -> Result<FileLocation, Status> {
+    if json.value_type != JSONValueType::Object {
+        eprintln!("Non-object given as a file location");
+        return Err(Status::COMPROMISED_DATA);
+    }
+
+    let mut disk_guid   = unwrap_json_var!(get_json_var::<Guid>(&json, BootEntry::KEY_DISK, Guid::ZERO, false, JSONValueType::String));
+    let disk_signature  = parse_disk_signature(&unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_DISK_SIGNATURE, String::new(), false, JSONValueType::String)))?;
+    let partition       = unwrap_json_var!(get_json_var::<u8>(&json, BootEntry::KEY_PARTITION, 0, true, JSONValueType::Number));
+    let fstype          = unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_FS, String::new(), true, JSONValueType::String));
+    let path            = unwrap_json_var!(get_json_var::<String>(&json, BootEntry::KEY_PATH, String::new(), true, JSONValueType::String));
+
+    // A location without a disk is on the bootloader's disk, which is filled in when it is read
+    if disk_signature != 0 {
+        disk_guid = Guid::ZERO;
+    }
+
+    Ok(FileLocation {
+        disk_guid,
+        disk_signature,
+        partition,
+        fstype,
+        path
+    })
+}
+
+/// Parses a hex-encoded MBR disk signature as shown by `blkid` (e.g. `"1a2b3c4d"`), where an empty string gives no signature.
+fn parse_disk_signature(value: &str) -> Result<u32, Status> {
+    if value.is_empty() {
+        return Ok(0);
+    }
+
+    match u32::from_str_radix(value.trim_start_matches("0x"), 16) {
+        Ok(ok) => Ok(ok),
+        Err(_) => {
+            eprintln!("Key \"{}\" must be a hexadecimal disk signature, got \"{}\"", BootEntry::KEY_DISK_SIGNATURE, value);
+            Err(Status::ABORTED)
+        }
+    }
+}
+
+/// Parses the initial ramdisk images of a boot entry, given as a path, or an array of paths and file locations.
+/// Empty paths are skipped, as older configs wrote an empty path for boot entries without an initial ramdisk.
+fn parse_initrd(json: JSONValue) -> Result<Vec<Initrd>, Status> {
+    match json.value_type {
+        // A single path, as written by older configs
+        JSONValueType::String => {
+            let path = json.read_string().unwrap();
+            if path.is_empty() {
+                Ok(Vec::new())
+            }
+            else {
+                Ok(vec![Initrd::Path(path.to_string())])
+            }
+        }
+        JSONValueType::Array => {
+            let mut ret = Vec::new();
+            for initrd_json in json.iter_array().unwrap() {
+                match initrd_json.value_type {
+                    JSONValueType::String => {
+                        let path = initrd_json.read_string().unwrap();
+                        if !path.is_empty() {
+                            ret.push(Initrd::Path(path.to_string()));
+                        }
+                    }
+                    JSONValueType::Object => ret.push(Initrd::Location(parse_file_location(initrd_json)?)),
+                    _ => {
+                        eprintln!("Key \"{}\" must only contain paths and file locations", BootEntry::KEY_INITRD);
+                        return Err(Status::ABORTED);
+                    }
+                }
+            }
+            Ok(ret)
+        }
+        _ => {
+            eprintln!("Key \"{}\" must be a string or an array", BootEntry::KEY_INITRD);
+            Err(Status::ABORTED)
+        }
+    }
+}
+
+/// Reads the boot entries of a drop-in file on another partition.
+///
+/// Drop-in files are JSON objects with a `bootentries` array, whose boot entries may extend the templates of the config.
+fn read_include(include: &FileLocation, templates: &BTreeMap<&str, JSONValue>) -> Result<Vec<BootEntry>, Status> {
+    let buffer = match include.read() {
+        Ok(ok) => ok,
+        Err(err) => {
+            // A missing drop-in should not stop the rest of the config from loading
+            wprintln!("Unable to read included file {}: {:?}", include.path, err);
+            return Ok(Vec::new());
+        }
+    };
+
+    let buffer_string = match String::from_utf8(buffer) {
+        Ok(ok) => ok,
+        Err(_) => {
+            eprintln!("Included file {} is not valid UTF-8", include.path);
+            return Err(Status::ABORTED);
+        }
+    };
+    let json = match JSONValue::load_and_verify(&buffer_string) {
+        Ok(ok) => ok,
+        Err(err) => {
+            eprintln!("Failed to parse included file {}: {}", include.path, err);
+            return Err(Status::ABORTED);
+        }
+    };
+
+    let boot_items = match json.get_key_value(Config::KEY_BOOT_ENTRIES) {
+        Ok(bootentries_json) if bootentries_json.value_type == JSONValueType::Array => {
+            parse_bootentries(bootentries_json, templates)?
+        }
+        _ => {
+            wprintln!("No boot entries detected in included file {}", include.path);
+            Vec::new()
+        }
+    };
+
+    let mut boot_entries = Vec::new();
+    for boot_item in boot_items {
+        match boot_item {
+            BootItem::Entry(mut boot_entry) => {
+                // Drop-in boot entries are not part of the config, so they must not be written back to it
+                boot_entry.generated = true;
+                boot_entries.push(boot_entry);
+            }
+            BootItem::Group(_) => {
+                wprintln!("Groups are not supported in included files, ignoring...");
+            }
+        }
+    }
+
+    Ok(boot_entries)
+}
+
+/// Parses a JSON object and attempts to return a corresponding `BootCondition`.
+fn parse_condition(json: JSONValue) -> Result<BootCondition, Status> {
+    let secure_boot         = get_optional_json_var::<bool>(&json, BootCondition::KEY_SECURE_BOOT, JSONValueType::Bool)?;
+    let smbios_manufacturer = get_optional_json_var::<String>(&json, BootCondition::KEY_SMBIOS_MANUFACTURER, JSONValueType::String)?;
+    let smbios_product      = get_optional_json_var::<String>(&json, BootCondition::KEY_SMBIOS_PRODUCT, JSONValueType::String)?;
+    let file_exists         = get_optional_json_var::<String>(&json, BootCondition::KEY_FILE_EXISTS, JSONValueType::String)?;
+    let disk_present        = get_optional_json_var::<Guid>(&json, BootCondition::KEY_DISK_PRESENT, JSONValueType::String)?;
+
+    Ok(BootCondition {
+        secure_boot,
+        smbios_manufacturer,
+        smbios_product,
+        file_exists,
+        disk_present
     })
 }
 
+/// Gets a variable from a JSON object, or `None` if the key is absent.
+fn get_optional_json_var<T: Default + Debug + FromStr + 'static>(json: &JSONValue, key: &str, json_type: JSONValueType) -> Result<Option<T>, Status> {
+    if json.get_key_value(key).is_err() {
+        return Ok(None);
+    }
+
+    // The key is present, so it must be of the right type
+    match get_json_var::<T>(json, key, T::default(), true, json_type) {
+        Ok(ok) => Ok(Some(ok)),
+        Err(err) => Err(err)
+    }
+}
+
 /// Gets a variable from a JSON object.
 fn get_json_var<T: Default + Debug + FromStr + 'static>(json: &JSONValue, key: &str, default: T, required: bool, json_type: JSONValueType) -> Result<T, Status> {
     if !match json.get_key_value(key) {
@@ -209,6 +759,7 @@ fn get_json_var<T: Default + Debug + FromStr + 'static>(json: &JSONValue, key: &
     const GUID_TYPE: TypeId         = TypeId::of::<Guid>();
     const I32_TYPE: TypeId          = TypeId::of::<i32>();
     const LOG_LEVEL_TYPE: TypeId    = TypeId::of::<LogLevel>();
+    const MENU_MODE_TYPE: TypeId    = TypeId::of::<MenuMode>();
     const STRING_TYPE: TypeId       = TypeId::of::<String>();
     const U8_TYPE: TypeId           = TypeId::of::<u8>();
 
@@ -234,6 +785,11 @@ fn get_json_var<T: Default + Debug + FromStr + 'static>(json: &JSONValue, key: &
                 value.read_string().unwrap()
             ).unwrap_unchecked()
         }),
+        (MENU_MODE_TYPE, {
+            T::from_str(
+                value.read_string().unwrap()
+            ).unwrap_unchecked()
+        }),
         (STRING_TYPE, {
             T::from_str(
                 value.read_string().unwrap()
@@ -0,0 +1,442 @@
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Write};
+
+use microjson::{JSONValue, JSONValueType};
+use uefi::{Guid, Status};
+
+use crate::*;
+use crate::wtcore::config::*;
+
+/// The number of spaces each level of a serialised JSON value is indented by.
+const INDENT_WIDTH: usize = 4;
+
+/// An owned JSON value, which can be built up and written out as canonical JSON.
+#[derive(Clone, Debug)]
+pub enum OwnedJSONValue {
+    Null,
+    Bool(bool),
+    Number(i64),
+    /// A string, kept in its escaped form (as read from a JSON document).
+    String(String),
+    Array(Vec<OwnedJSONValue>),
+    /// An object, whose keys are kept in insertion order.
+    Object(Vec<(String, OwnedJSONValue)>),
+}
+
+impl OwnedJSONValue {
+    /// Copies a parsed JSON value.
+    pub fn from_json(json: &JSONValue) -> Result<Self, Status> {
+        let ret = match json.value_type {
+            JSONValueType::Null => OwnedJSONValue::Null,
+            JSONValueType::Bool => OwnedJSONValue::Bool(json.read_boolean().unwrap()),
+            JSONValueType::Number => {
+                match json.read_integer() {
+                    Ok(ok) => OwnedJSONValue::Number(ok as i64),
+                    Err(_) => {
+                        eprintln!("Non-integer numbers are not supported");
+                        return Err(Status::ABORTED);
+                    }
+                }
+            }
+            JSONValueType::String => OwnedJSONValue::String(json.read_string().unwrap().to_string()),
+            JSONValueType::Array => {
+                let mut array = Vec::new();
+                for element in json.iter_array().unwrap() {
+                    array.push(OwnedJSONValue::from_json(&element)?);
+                }
+                OwnedJSONValue::Array(array)
+            }
+            JSONValueType::Object => {
+                let mut object = Vec::new();
+                for pair in json.iter_object().unwrap() {
+                    let (key, value) = match pair {
+                        Ok(ok) => ok,
+                        Err(err) => {
+                            eprintln!("Failed to parse object: {}", err);
+                            return Err(Status::ABORTED);
+                        }
+                    };
+                    object.push((key.to_string(), OwnedJSONValue::from_json(&value)?));
+                }
+                OwnedJSONValue::Object(object)
+            }
+            JSONValueType::Error => {
+                return Err(Status::ABORTED);
+            }
+        };
+
+        Ok(ret)
+    }
+
+    /// Creates a JSON string value.
+    fn string(value: &str) -> Self {
+        OwnedJSONValue::String(value.to_string())
+    }
+
+    /// Gets the value of a key if this is an object containing it.
+    pub fn get(&self, key: &str) -> Option<&OwnedJSONValue> {
+        match self {
+            OwnedJSONValue::Object(object) => object.iter().find(|t| t.0 == key).map(|t| &t.1),
+            _ => None
+        }
+    }
+
+    /// Gets the mutable value of a key if this is an object containing it.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut OwnedJSONValue> {
+        match self {
+            OwnedJSONValue::Object(object) => object.iter_mut().find(|t| t.0 == key).map(|t| &mut t.1),
+            _ => None
+        }
+    }
+
+    /// Sets the value of a key if this is an object, replacing any existing value.
+    pub fn insert(&mut self, key: &str, value: OwnedJSONValue) {
+        if let Some(existing) = self.get_mut(key) {
+            *existing = value;
+        }
+        else if let OwnedJSONValue::Object(object) = self {
+            object.push((key.to_string(), value));
+        }
+    }
+
+    /// Writes this value as JSON, indented to the given level.
+    fn write(&self, out: &mut String, level: usize) {
+        let indent = |out: &mut String, level: usize| {
+            for _ in 0..level * INDENT_WIDTH {
+                out.push(' ');
+            }
+        };
+
+        match self {
+            OwnedJSONValue::Null => out.push_str("null"),
+            OwnedJSONValue::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            OwnedJSONValue::Number(value) => {
+                let _ = write!(out, "{}", value);
+            }
+            OwnedJSONValue::String(value) => write_string(out, value),
+            OwnedJSONValue::Array(array) => {
+                if array.is_empty() {
+                    out.push_str("[]");
+                    return;
+                }
+
+                out.push_str("[\n");
+                for (i, element) in array.iter().enumerate() {
+                    indent(out, level + 1);
+                    element.write(out, level + 1);
+                    if i + 1 < array.len() {
+                        out.push(',');
+                    }
+                    out.push('\n');
+                }
+                indent(out, level);
+                out.push(']');
+            }
+            OwnedJSONValue::Object(object) => {
+                if object.is_empty() {
+                    out.push_str("{}");
+                    return;
+                }
+
+                out.push_str("{\n");
+                for (i, (key, value)) in object.iter().enumerate() {
+                    indent(out, level + 1);
+                    write_string(out, key);
+                    out.push_str(": ");
+                    value.write(out, level + 1);
+                    if i + 1 < object.len() {
+                        out.push(',');
+                    }
+                    out.push('\n');
+                }
+                indent(out, level);
+                out.push('}');
+            }
+        }
+    }
+}
+
+impl PartialEq for OwnedJSONValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (OwnedJSONValue::Null, OwnedJSONValue::Null) => true,
+            (OwnedJSONValue::Bool(a), OwnedJSONValue::Bool(b)) => a == b,
+            (OwnedJSONValue::Number(a), OwnedJSONValue::Number(b)) => a == b,
+            (OwnedJSONValue::String(a), OwnedJSONValue::String(b)) => a == b,
+            (OwnedJSONValue::Array(a), OwnedJSONValue::Array(b)) => a == b,
+            // The order of keys in an object is insignificant
+            (OwnedJSONValue::Object(a), OwnedJSONValue::Object(b)) => {
+                a.len() == b.len() && a.iter().all(|(key, value)| other.get(key) == Some(value))
+                    && b.iter().all(|(key, _)| self.get(key).is_some())
+            }
+            _ => false
+        }
+    }
+}
+
+impl Display for OwnedJSONValue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut out = String::new();
+        self.write(&mut out, 0);
+        f.write_str(&out)
+    }
+}
+
+/// Writes a quoted JSON string.
+///
+/// Strings are kept in the escaped form they were read in, so existing escape sequences are copied as-is
+/// and only characters which cannot appear in a JSON string are escaped.
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                match chars.next() {
+                    Some(escaped) => {
+                        out.push('\\');
+                        out.push(escaped);
+                    }
+                    // A trailing backslash would escape the closing quote
+                    None => out.push_str("\\\\")
+                }
+            }
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c)
+        }
+    }
+
+    out.push('"');
+}
+
+impl Config {
+    /// Converts the configuration to JSON which, when parsed, results in the same configuration.
+    ///
+    /// Boot entries generated from other sources (e.g. BLS entries) are not included.
+    pub fn to_json(&self) -> OwnedJSONValue {
+        let mut ret = vec![
+            (Config::KEY_VERSION.to_string(), OwnedJSONValue::Number(CONFIG_VERSION as i64)),
+            (Config::KEY_LOG_LEVEL.to_string(), OwnedJSONValue::string(&format!("{:?}", self.log_level))),
+            (Config::KEY_TIMEOUT.to_string(), OwnedJSONValue::Number(self.timeout as i64)),
+            (Config::KEY_EXIT.to_string(), OwnedJSONValue::Bool(self.exit)),
+            (Config::KEY_FIRMWARE.to_string(), OwnedJSONValue::Bool(self.firmware)),
+            (Config::KEY_EDIT_CONFIG.to_string(), OwnedJSONValue::Bool(self.edit_config)),
+            (Config::KEY_MENU_CLEAR.to_string(), OwnedJSONValue::Bool(self.menu_clear)),
+            (Config::KEY_MENU_MODE.to_string(), OwnedJSONValue::string(self.menu_mode.as_str())),
+            (Config::KEY_NV_CONFIG.to_string(), OwnedJSONValue::Bool(self.nv_config)),
+            (Config::KEY_BLS.to_string(), OwnedJSONValue::Bool(self.bls)),
+            (Config::KEY_DISK_CACHE.to_string(), OwnedJSONValue::Number(self.disk_cache as i64)),
+        ];
+
+        // The password is optional, so it is only written if set
+        if let Some(password) = &self.password {
+            ret.push((Config::KEY_PASSWORD.to_string(), OwnedJSONValue::string(&password.to_string())));
+        }
+
+        // Autodetection is opt-in, so it is only written if enabled
+        if self.autodetect.is_enabled() {
+            ret.push((Config::KEY_AUTODETECT.to_string(), self.autodetect.to_json()));
+        }
+        if !self.variables.is_empty() {
+            ret.push((
+                Config::KEY_VARIABLES.to_string(),
+                OwnedJSONValue::Object(
+                    self.variables.iter()
+                        .map(|(name, value)| (name.clone(), OwnedJSONValue::String(value.clone())))
+                        .collect()
+                )
+            ));
+        }
+        if !self.includes.is_empty() {
+            ret.push((
+                Config::KEY_INCLUDES.to_string(),
+                OwnedJSONValue::Array(self.includes.iter().map(|t| t.to_json()).collect())
+            ));
+        }
+        if !self.templates.is_empty() {
+            ret.push((Config::KEY_TEMPLATES.to_string(), OwnedJSONValue::Array(self.templates.clone())));
+        }
+        ret.push((
+            Config::KEY_BOOT_ENTRIES.to_string(),
+            bootentries_to_json(&self.boot_entries, &self.templates)
+        ));
+
+        OwnedJSONValue::Object(ret)
+    }
+}
+
+impl Autodetect {
+    /// Converts the autodetection settings to JSON.
+    pub fn to_json(&self) -> OwnedJSONValue {
+        OwnedJSONValue::Object(vec![
+            (Autodetect::KEY_UKI.to_string(), OwnedJSONValue::Bool(self.uki)),
+            (Autodetect::KEY_WINDOWS.to_string(), OwnedJSONValue::Bool(self.windows)),
+            (Autodetect::KEY_EFI.to_string(), OwnedJSONValue::Bool(self.efi)),
+        ])
+    }
+}
+
+impl BootGroup {
+    /// Converts the group to JSON, omitting properties inherited from the given templates.
+    pub fn to_json(&self, templates: &[OwnedJSONValue]) -> OwnedJSONValue {
+        OwnedJSONValue::Object(vec![
+            (BootGroup::KEY_GROUP.to_string(), OwnedJSONValue::string(&self.name)),
+            (Config::KEY_BOOT_ENTRIES.to_string(), bootentries_to_json(&self.boot_entries, templates)),
+        ])
+    }
+}
+
+impl FileLocation {
+    /// Converts the file location to JSON, omitting the disk if it is the bootloader's.
+    pub fn to_json(&self) -> OwnedJSONValue {
+        let mut ret = Vec::new();
+        if self.disk_guid != Guid::ZERO || self.disk_signature != 0 {
+            ret.push(disk_to_json(&self.disk_guid, self.disk_signature));
+        }
+        ret.push((BootEntry::KEY_PARTITION.to_string(), OwnedJSONValue::Number(self.partition as i64)));
+        ret.push((BootEntry::KEY_FS.to_string(), OwnedJSONValue::string(&self.fstype)));
+        ret.push((BootEntry::KEY_PATH.to_string(), OwnedJSONValue::string(&self.path)));
+
+        OwnedJSONValue::Object(ret)
+    }
+}
+
+impl BootCondition {
+    /// Converts the conditions to JSON, omitting those which always hold.
+    pub fn to_json(&self) -> OwnedJSONValue {
+        let mut ret = Vec::new();
+
+        if let Some(secure_boot) = self.secure_boot {
+            ret.push((BootCondition::KEY_SECURE_BOOT.to_string(), OwnedJSONValue::Bool(secure_boot)));
+        }
+        if let Some(smbios_manufacturer) = &self.smbios_manufacturer {
+            ret.push((BootCondition::KEY_SMBIOS_MANUFACTURER.to_string(), OwnedJSONValue::string(smbios_manufacturer)));
+        }
+        if let Some(smbios_product) = &self.smbios_product {
+            ret.push((BootCondition::KEY_SMBIOS_PRODUCT.to_string(), OwnedJSONValue::string(smbios_product)));
+        }
+        if let Some(file_exists) = &self.file_exists {
+            ret.push((BootCondition::KEY_FILE_EXISTS.to_string(), OwnedJSONValue::string(file_exists)));
+        }
+        if let Some(disk_present) = &self.disk_present {
+            ret.push((BootCondition::KEY_DISK_PRESENT.to_string(), guid_to_json(disk_present)));
+        }
+
+        OwnedJSONValue::Object(ret)
+    }
+}
+
+impl BootEntry {
+    /// Converts the boot entry to JSON, omitting properties inherited from the given templates.
+    pub fn to_json(&self, templates: &[OwnedJSONValue]) -> OwnedJSONValue {
+        let mut ret = vec![(BootEntry::KEY_NAME.to_string(), OwnedJSONValue::string(&self.name))];
+        if !self.extends.is_empty() {
+            ret.push((BootEntry::KEY_EXTENDS.to_string(), OwnedJSONValue::string(&self.extends)));
+        }
+
+        // Properties are written if they differ from what would be inherited (or defaulted) otherwise
+        let inheritable = |key: &str, value: OwnedJSONValue, default: Option<OwnedJSONValue>| {
+            let omit = match get_inherited_value(templates, &self.extends, key) {
+                Some(inherited) => *inherited == value,
+                None => default.is_some_and(|t| t == value)
+            };
+            if omit { None } else { Some((key.to_string(), value)) }
+        };
+        ret.extend(inheritable(BootEntry::KEY_REMOVABLE, OwnedJSONValue::Bool(self.removable), Some(OwnedJSONValue::Bool(false))));
+        ret.extend(inheritable(BootEntry::KEY_HIDDEN, OwnedJSONValue::Bool(self.hidden), Some(OwnedJSONValue::Bool(false))));
+        ret.extend(inheritable(BootEntry::KEY_LOCKED, OwnedJSONValue::Bool(self.locked), Some(OwnedJSONValue::Bool(false))));
+        ret.extend(inheritable(BootEntry::KEY_CONDITION, self.condition.to_json(), Some(OwnedJSONValue::Object(Vec::new()))));
+        let (disk_key, disk_value) = disk_to_json(&self.disk_guid, self.disk_signature);
+        ret.extend(inheritable(&disk_key, disk_value, Some(guid_to_json(&Guid::ZERO))));
+        ret.extend(inheritable(BootEntry::KEY_PARTITION, OwnedJSONValue::Number(self.partition as i64), Some(OwnedJSONValue::Number(0))));
+        ret.extend(inheritable(BootEntry::KEY_PART_UUID, guid_to_json(&self.part_uuid), Some(guid_to_json(&Guid::ZERO))));
+        ret.extend(inheritable(BootEntry::KEY_PART_LABEL, OwnedJSONValue::string(&self.part_label), Some(OwnedJSONValue::string(""))));
+        ret.extend(inheritable(BootEntry::KEY_PART_TYPE, guid_to_json(&self.part_type), Some(guid_to_json(&Guid::ZERO))));
+        ret.extend(inheritable(BootEntry::KEY_FS_UUID, OwnedJSONValue::string(&self.fs_uuid), Some(OwnedJSONValue::string(""))));
+        ret.extend(inheritable(BootEntry::KEY_FS_LABEL, OwnedJSONValue::string(&self.fs_label), Some(OwnedJSONValue::string(""))));
+        ret.extend(inheritable(BootEntry::KEY_FS, OwnedJSONValue::string(&self.fstype), None));
+        ret.extend(inheritable(BootEntry::KEY_PROGTYPE, OwnedJSONValue::string(&self.ostype), None));
+        // The path is never inherited
+        ret.push((BootEntry::KEY_PATH.to_string(), OwnedJSONValue::string(&self.path)));
+        ret.extend(inheritable(BootEntry::KEY_INITRD, initrd_to_json(&self.initrd), Some(OwnedJSONValue::Array(Vec::new()))));
+        ret.extend(inheritable(BootEntry::KEY_ARGS, OwnedJSONValue::string(&self.args), Some(OwnedJSONValue::string(""))));
+
+        OwnedJSONValue::Object(ret)
+    }
+}
+
+/// Converts the initial ramdisk images of a boot entry to JSON, keeping a single path as a string.
+fn initrd_to_json(initrd: &[Initrd]) -> OwnedJSONValue {
+    if let [Initrd::Path(path)] = initrd {
+        return OwnedJSONValue::string(path);
+    }
+
+    OwnedJSONValue::Array(
+        initrd.iter()
+            .map(|t| match t {
+                Initrd::Path(path) => OwnedJSONValue::string(path),
+                Initrd::Location(location) => location.to_json()
+            })
+            .collect()
+    )
+}
+
+/// Converts an array of boot entries and groups to JSON in their order, skipping generated boot entries.
+fn bootentries_to_json(boot_entries: &[BootItem], templates: &[OwnedJSONValue]) -> OwnedJSONValue {
+    OwnedJSONValue::Array(
+        boot_entries.iter()
+            .filter_map(|t| match t {
+                BootItem::Entry(entry) if entry.generated => None,
+                BootItem::Entry(entry) => Some(entry.to_json(templates)),
+                BootItem::Group(group) => Some(group.to_json(templates))
+            })
+            .collect()
+    )
+}
+
+/// Gets the value of a key from the first template in a chain which defines it.
+fn get_inherited_value<'a>(templates: &'a [OwnedJSONValue], extends: &str, key: &str) -> Option<&'a OwnedJSONValue> {
+    let mut template_name = extends.to_string();
+    // Chains are checked for cycles while parsing, but guard against them regardless
+    for _ in 0..templates.len() {
+        if template_name.is_empty() {
+            break;
+        }
+
+        let template = templates.iter().find(|t| t.get(BootEntry::KEY_NAME) == Some(&OwnedJSONValue::String(template_name.clone())))?;
+        if let Some(value) = template.get(key) {
+            return Some(value);
+        }
+        template_name = match template.get(BootEntry::KEY_EXTENDS) {
+            Some(OwnedJSONValue::String(some)) => some.clone(),
+            _ => String::new()
+        };
+    }
+
+    None
+}
+
+/// Converts a GUID to a JSON string.
+fn guid_to_json(guid: &Guid) -> OwnedJSONValue {
+    OwnedJSONValue::String(guid.to_string())
+}
+
+/// Converts the disk of a boot entry or file location to a JSON key/value pair, preferring the MBR disk signature if set.
+fn disk_to_json(disk_guid: &Guid, disk_signature: u32) -> (String, OwnedJSONValue) {
+    if disk_signature != 0 {
+        (BootEntry::KEY_DISK_SIGNATURE.to_string(), OwnedJSONValue::String(format!("{:08x}", disk_signature)))
+    }
+    else {
+        (BootEntry::KEY_DISK.to_string(), guid_to_json(disk_guid))
+    }
+}
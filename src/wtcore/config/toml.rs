@@ -0,0 +1,353 @@
+extern crate alloc;
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use uefi::Status;
+
+use crate::*;
+use crate::wtcore::config::serialize::OwnedJSONValue;
+
+/// Converts a TOML document to the equivalent JSON document, so it can be parsed like any other config.
+///
+/// Only the subset of TOML needed to describe a config is supported: tables, arrays of tables, dotted and quoted keys,
+/// single-line strings, integers, booleans, arrays, and inline tables.
+pub fn toml_to_json(buffer: &[u8]) -> Result<String, Status> {
+    let document = match core::str::from_utf8(buffer) {
+        Ok(ok) => ok,
+        Err(_) => {
+            eprintln!("TOML config is not valid UTF-8");
+            return Err(Status::ABORTED);
+        }
+    };
+
+    let mut parser = TOMLParser {
+        chars: document.chars().collect(),
+        position: 0,
+        line: 1
+    };
+    match parser.parse_document() {
+        Ok(ok) => Ok(ok.to_string()),
+        Err(err) => {
+            eprintln!("Failed to parse TOML config on line {}: {}", parser.line, err);
+            Err(Status::ABORTED)
+        }
+    }
+}
+
+/// A parser over the characters of a TOML document.
+struct TOMLParser {
+    chars: Vec<char>,
+    position: usize,
+    /// The line the parser is currently on, for error messages.
+    line: usize
+}
+
+impl TOMLParser {
+    /// Parses the whole document into a JSON object.
+    fn parse_document(&mut self) -> Result<OwnedJSONValue, &'static str> {
+        let mut root = OwnedJSONValue::Object(Vec::new());
+        // The path of the table key/value pairs are currently being added to
+        let mut current_table: Vec<String> = Vec::new();
+
+        loop {
+            self.skip_whitespace(true);
+            let c = match self.peek() {
+                Some(some) => some,
+                None => break
+            };
+
+            if c == '[' {
+                self.position += 1;
+                let is_array = self.peek() == Some('[');
+                if is_array {
+                    self.position += 1;
+                }
+
+                self.skip_whitespace(false);
+                let path = self.parse_key()?;
+                self.skip_whitespace(false);
+                self.expect(']')?;
+                if is_array {
+                    self.expect(']')?;
+
+                    // Each [[header]] appends a new table to the array
+                    let (name, parent_path) = path.split_last().unwrap();
+                    let parent = table_at(&mut root, parent_path).ok_or("Key is not a table")?;
+                    if parent.get(name).is_none() {
+                        parent.insert(name, OwnedJSONValue::Array(Vec::new()));
+                    }
+                    match parent.get_mut(name) {
+                        Some(OwnedJSONValue::Array(array)) => array.push(OwnedJSONValue::Object(Vec::new())),
+                        _ => return Err("Key is not an array of tables")
+                    }
+                }
+                else if table_at(&mut root, &path).is_none() {
+                    return Err("Key is not a table");
+                }
+
+                current_table = path;
+            }
+            else {
+                let path = self.parse_key()?;
+                self.skip_whitespace(false);
+                self.expect('=')?;
+                self.skip_whitespace(false);
+                let value = self.parse_value()?;
+
+                let table = table_at(&mut root, &current_table).ok_or("Key is not a table")?;
+                insert_dotted(table, &path, value)?;
+            }
+
+            // Only a comment may follow on the same line
+            self.skip_whitespace(false);
+            match self.peek() {
+                None | Some('\n') | Some('\r') | Some('#') => {}
+                Some(_) => return Err("Expected a new line")
+            }
+        }
+
+        Ok(root)
+    }
+
+    /// Parses a (possibly dotted) key into its parts.
+    fn parse_key(&mut self) -> Result<Vec<String>, &'static str> {
+        let mut ret = Vec::new();
+
+        loop {
+            self.skip_whitespace(false);
+            let part = match self.peek() {
+                Some('"') => {
+                    self.position += 1;
+                    self.parse_basic_string()?
+                }
+                Some('\'') => {
+                    self.position += 1;
+                    self.parse_literal_string()?
+                }
+                _ => {
+                    let start = self.position;
+                    while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        self.position += 1;
+                    }
+                    if start == self.position {
+                        return Err("Expected a key");
+                    }
+                    self.chars[start..self.position].iter().collect()
+                }
+            };
+            ret.push(part);
+
+            self.skip_whitespace(false);
+            if self.peek() != Some('.') {
+                return Ok(ret);
+            }
+            self.position += 1;
+        }
+    }
+
+    /// Parses a value.
+    fn parse_value(&mut self) -> Result<OwnedJSONValue, &'static str> {
+        match self.peek() {
+            Some('"') => {
+                self.position += 1;
+                if self.peek() == Some('"') && self.chars.get(self.position + 1) == Some(&'"') {
+                    return Err("Multi-line strings are not supported");
+                }
+                Ok(OwnedJSONValue::String(self.parse_basic_string()?))
+            }
+            Some('\'') => {
+                self.position += 1;
+                Ok(OwnedJSONValue::String(self.parse_literal_string()?))
+            }
+            Some('[') => {
+                self.position += 1;
+                let mut array = Vec::new();
+                loop {
+                    self.skip_whitespace(true);
+                    if self.peek() == Some(']') {
+                        self.position += 1;
+                        return Ok(OwnedJSONValue::Array(array));
+                    }
+
+                    array.push(self.parse_value()?);
+
+                    // Trailing commas are allowed
+                    self.skip_whitespace(true);
+                    match self.peek() {
+                        Some(',') => self.position += 1,
+                        Some(']') => {}
+                        _ => return Err("Expected ',' or ']' in array")
+                    }
+                }
+            }
+            Some('{') => {
+                self.position += 1;
+                let mut table = OwnedJSONValue::Object(Vec::new());
+                self.skip_whitespace(false);
+                if self.peek() == Some('}') {
+                    self.position += 1;
+                    return Ok(table);
+                }
+                loop {
+                    let path = self.parse_key()?;
+                    self.skip_whitespace(false);
+                    self.expect('=')?;
+                    self.skip_whitespace(false);
+                    let value = self.parse_value()?;
+                    insert_dotted(&mut table, &path, value)?;
+
+                    self.skip_whitespace(false);
+                    match self.peek() {
+                        Some(',') => self.position += 1,
+                        Some('}') => {
+                            self.position += 1;
+                            return Ok(table);
+                        }
+                        _ => return Err("Expected ',' or '}' in inline table")
+                    }
+                    self.skip_whitespace(false);
+                }
+            }
+            Some('t') | Some('f') => {
+                let start = self.position;
+                while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    self.position += 1;
+                }
+                match self.chars[start..self.position].iter().collect::<String>().as_str() {
+                    "true" => Ok(OwnedJSONValue::Bool(true)),
+                    "false" => Ok(OwnedJSONValue::Bool(false)),
+                    _ => Err("Expected a value")
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => {
+                let start = self.position;
+                self.position += 1;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '_') {
+                    self.position += 1;
+                }
+                let number: String = self.chars[start..self.position].iter().filter(|c| **c != '_').collect();
+                match number.parse::<i64>() {
+                    Ok(ok) => Ok(OwnedJSONValue::Number(ok)),
+                    Err(_) => Err("Only decimal integers are supported")
+                }
+            }
+            _ => Err("Expected a value")
+        }
+    }
+
+    /// Parses the rest of a basic (double-quoted) string, keeping its escape sequences.
+    ///
+    /// TOML and JSON share their escape sequences, so the string is already in the form JSON strings are kept in.
+    fn parse_basic_string(&mut self) -> Result<String, &'static str> {
+        let mut ret = String::new();
+
+        loop {
+            match self.next() {
+                Some('"') => return Ok(ret),
+                Some('\\') => {
+                    ret.push('\\');
+                    match self.next() {
+                        Some('\n') | None => return Err("Unterminated string"),
+                        Some(c) => ret.push(c)
+                    }
+                }
+                Some('\n') | None => return Err("Unterminated string"),
+                Some(c) => ret.push(c)
+            }
+        }
+    }
+
+    /// Parses the rest of a literal (single-quoted) string, escaping it as a JSON string.
+    fn parse_literal_string(&mut self) -> Result<String, &'static str> {
+        let mut ret = String::new();
+
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(ret),
+                // Literal strings have no escape sequences, so backslashes are literal
+                Some('\\') => ret.push_str("\\\\"),
+                Some('\n') | None => return Err("Unterminated string"),
+                Some(c) => ret.push(c)
+            }
+        }
+    }
+
+    /// Skips spaces, tabs, and (if allowed) new lines and comments.
+    fn skip_whitespace(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => {}
+                '\r' | '\n' if newlines => {}
+                '#' if newlines => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.position += 1;
+                    }
+                    continue;
+                }
+                _ => return
+            }
+            self.next();
+        }
+    }
+
+    /// Consumes a character, failing if it is not the expected one.
+    fn expect(&mut self, expected: char) -> Result<(), &'static str> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(match expected {
+                ']' => "Expected ']'",
+                '=' => "Expected '='",
+                _ => "Unexpected character"
+            })
+        }
+    }
+
+    /// Returns the current character without consuming it.
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    /// Consumes and returns the current character.
+    fn next(&mut self) -> Option<char> {
+        let ret = self.peek();
+        if ret.is_some() {
+            self.position += 1;
+        }
+        if ret == Some('\n') {
+            self.line += 1;
+        }
+        ret
+    }
+}
+
+/// Gets the table at a path, creating any missing tables. The last table of an array of tables is used.
+fn table_at<'a>(table: &'a mut OwnedJSONValue, path: &[String]) -> Option<&'a mut OwnedJSONValue> {
+    let (name, rest) = match path.split_first() {
+        Some(some) => some,
+        None => {
+            return if matches!(table, OwnedJSONValue::Object(_)) { Some(table) } else { None };
+        }
+    };
+
+    if table.get(name).is_none() {
+        table.insert(name, OwnedJSONValue::Object(Vec::new()));
+    }
+    let next = match table.get_mut(name)? {
+        OwnedJSONValue::Array(array) => array.last_mut()?,
+        other => other
+    };
+    table_at(next, rest)
+}
+
+/// Inserts a value at a dotted key into a table, failing if the key is already defined.
+fn insert_dotted(table: &mut OwnedJSONValue, path: &[String], value: OwnedJSONValue) -> Result<(), &'static str> {
+    let (name, parent_path) = path.split_last().unwrap();
+    let parent = table_at(table, parent_path).ok_or("Key is not a table")?;
+    if parent.get(name).is_some() {
+        return Err("Duplicate key");
+    }
+
+    parent.insert(name, value);
+    Ok(())
+}
@@ -0,0 +1,59 @@
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+
+use crate::*;
+use crate::wtcore::config::BootEntry;
+
+/// Built-in variable containing the disk GUID of the bootloader.
+pub const BUILTIN_BOOTLOADER_DISK: &str = "bootloader_disk";
+/// Built-in variable containing the partition GUID of the bootloader.
+pub const BUILTIN_BOOTLOADER_PARTITION: &str = "bootloader_partition";
+/// Built-in variable containing the name of the boot entry being expanded.
+pub const BUILTIN_ENTRY_NAME: &str = "entry_name";
+
+/// Names of all the built-in variables, which cannot be redefined.
+pub const BUILTINS: [&str; 3] = [BUILTIN_BOOTLOADER_DISK, BUILTIN_BOOTLOADER_PARTITION, BUILTIN_ENTRY_NAME];
+
+/// Replaces every `${name}` in a string with the value of the named variable.
+///
+/// References to undefined variables are left untouched.
+pub fn expand_variables(input: &str, entry: &BootEntry, variables: &BTreeMap<String, String>) -> String {
+    let mut ret = String::new();
+
+    let mut remaining = input;
+    while let Some(start) = remaining.find("${") {
+        ret.push_str(&remaining[..start]);
+        remaining = &remaining[start..];
+
+        // An unterminated reference is copied verbatim
+        let end = match remaining.find('}') {
+            Some(some) => some,
+            None => break
+        };
+
+        let name = &remaining[2..end];
+        match get_variable(name, entry, variables) {
+            Some(value) => ret.push_str(&value),
+            None => {
+                wprintln!("Undefined variable \"{}\" in boot entry \"{}\"", name, entry.name);
+                ret.push_str(&remaining[..=end]);
+            }
+        }
+        remaining = &remaining[end+1..];
+    }
+    ret.push_str(remaining);
+
+    ret
+}
+
+/// Gets the value of a built-in or user-defined variable.
+fn get_variable(name: &str, entry: &BootEntry, variables: &BTreeMap<String, String>) -> Option<String> {
+    match name {
+        BUILTIN_BOOTLOADER_DISK => Some(dev::BOOTLOADER_DISK_GUID.to_string()),
+        BUILTIN_BOOTLOADER_PARTITION => Some(dev::BOOTLOADER_PARTITION_GUID.to_string()),
+        BUILTIN_ENTRY_NAME => Some(entry.name.clone()),
+        _ => variables.get(name).cloned()
+    }
+}
@@ -1,14 +1,23 @@
 extern crate alloc;
 
+use alloc::string::ToString;
 use alloc::vec::Vec;
 
 use uefi::fs::FileSystem;
-use uefi::Status;
+use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode};
+use uefi::{CStr16, CString16, Status};
 
 use crate::*;
 use crate::wtcore::config::*;
+use crate::wtcore::config::integrity::{editing_locked, enroll_config_hash, hash_enrolled};
+use crate::wtcore::config::load::{loaded_from_fallback, locate_config};
+use crate::wtcore::options::LOAD_OPTIONS;
 
-/// Writes to a byte array to the bootloader configuration file, overwriting the existing contents.
+/// Writes to a byte array to the bootloader configuration file (of whichever format is in use), overwriting the existing contents.
+///
+/// The buffer is first written to a temporary file next to the configuration file, which is then renamed to replace it,
+/// so a failed write leaves the existing configuration file intact. Should the system lose power after the configuration file is removed but before
+/// the temporary file is renamed, `load_config` finishes renaming it on the next boot. If a hash of the configuration file is enrolled, it is updated to match.
 pub fn write_config(buffer: &Vec<u8>) -> Result<(), Status> {
     // Attempt to get the file system containing the bootloader - the config file should be in the same file system
     let mut efifs = match uefi::boot::get_image_file_system(image_handle!()) {
@@ -16,13 +25,152 @@ pub fn write_config(buffer: &Vec<u8>) -> Result<(), Status> {
         Err(err) => return Err(err.status()),
     };
 
+    // File system drivers are read-only, so only configs on the bootloader's file system can be written
+    if LOAD_OPTIONS.config_location.is_some() {
+        eprintln!("Cannot write a config on another partition");
+        return Err(Status::UNSUPPORTED);
+    }
+
+    // A verified config can only be replaced by one that is trusted outside the bootloader
+    if editing_locked() {
+        eprintln!("Cannot write the config while it is verified");
+        return Err(Status::SECURITY_VIOLATION);
+    }
+
+    // Write to whichever config file is in use
+    let (_, config_path) = locate_config()?;
+    let config_path: &CStr16 = &config_path;
+
     // If there's a directory at the config path, delete it
-    if efifs.try_exists(CONFIG_PATH).unwrap() && efifs.metadata(CONFIG_PATH).unwrap().is_directory() {
-        efifs.remove_dir_all(CONFIG_PATH).unwrap();
+    if efifs.try_exists(config_path).unwrap() && efifs.metadata(config_path).unwrap().is_directory() {
+        efifs.remove_dir_all(config_path).unwrap();
     }
 
-    // Overwrites the config file with the buffer's content
-    efifs.write(CONFIG_PATH, buffer).unwrap();
+    let temp_path = temp_config_path(config_path)?;
+    let temp_path: &CStr16 = &temp_path;
+
+    // Write the buffer's content to the temporary file and make sure it arrived intact
+    if efifs.try_exists(temp_path).unwrap_or(false) && efifs.remove_file(temp_path).is_err() {
+        eprintln!("Failed to remove stale temporary config file");
+        return Err(Status::ABORTED);
+    }
+    if efifs.write(temp_path, buffer).is_err() {
+        eprintln!("Failed to write temporary config file");
+        return Err(Status::ABORTED);
+    }
+    match efifs.read(temp_path) {
+        Ok(ok) if ok == *buffer => {}
+        _ => {
+            eprintln!("Temporary config file does not match what was written");
+            let _ = efifs.remove_file(temp_path);
+            return Err(Status::VOLUME_CORRUPTED);
+        }
+    }
+
+    // Replace the config file with the temporary file - renaming fails if the config file still exists
+    if efifs.try_exists(config_path).unwrap_or(false) && efifs.remove_file(config_path).is_err() {
+        eprintln!("Failed to remove config file");
+        return Err(Status::ABORTED);
+    }
+    // The file system is opened exclusively, so it must be closed before the file can be renamed
+    drop(efifs);
+    if rename_file(temp_path, config_path).is_err() {
+        eprintln!("Failed to replace config file");
+        return Err(Status::ABORTED);
+    }
+
+    // Changes made from the bootloader are trusted, so the enrolled hash follows them
+    if hash_enrolled() {
+        enroll_config_hash(buffer)?;
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Serialises a configuration and writes it to the bootloader configuration file.
+///
+/// Configurations are serialised as JSON, so this fails if the configuration file is written in TOML.
+pub fn save_config(config: &Config) -> Result<(), Status> {
+    if locate_config()?.0 != ConfigFormat::JSON {
+        eprintln!("Saving the config is only supported for JSON config files");
+        return Err(Status::UNSUPPORTED);
+    }
+
+    write_config(&config.to_json().to_string().into_bytes())
+}
+
+/// Makes a top-level boot entry the default by moving it to the front of the boot entries in the bootloader configuration file.
+pub fn set_default_entry(name: &str) -> Result<(), Status> {
+    // Saving a fallback copy would silently replace the config file with it
+    if loaded_from_fallback() {
+        eprintln!("Cannot change the config while a fallback copy of it is loaded");
+        return Err(Status::ABORTED);
+    }
+
+    let mut config = WRITTEN_CONFIG.read().clone();
+    let idx = match config.boot_entries.iter().position(|t| matches!(t, BootItem::Entry(entry) if entry.name == name)) {
+        Some(some) => some,
+        None => {
+            eprintln!("Boot entry \"{}\" is not a top-level boot entry of the config", name);
+            return Err(Status::NOT_FOUND);
+        }
+    };
+    let boot_item = config.boot_entries.remove(idx);
+    config.boot_entries.insert(0, boot_item);
+
+    save_config(&config)
+}
+
+/// Returns the path of the temporary file a configuration file is replaced with.
+///
+/// The temporary file is kept in the same directory, so that it can replace the configuration file by being renamed.
+pub fn temp_config_path(config_path: &CStr16) -> Result<CString16, Status> {
+    match CString16::try_from(format!("{}{}", config_path, CONFIG_TEMP_SUFFIX).as_str()) {
+        Ok(ok) => Ok(ok),
+        Err(_) => Err(Status::INVALID_PARAMETER)
+    }
+}
+
+/// Renames a file on the bootloader's file system to the name of another path in the same directory.
+///
+/// `FileSystem::rename` copies the file, whereas changing the name in its `FileInfo` only rewrites its directory entry.
+pub fn rename_file(path: &CStr16, new_path: &CStr16) -> Result<(), Status> {
+    let mut sfs = match uefi::boot::get_image_file_system(image_handle!()) {
+        Ok(ok) => ok,
+        Err(err) => return Err(err.status())
+    };
+    let mut file = match sfs.open_volume().and_then(|mut t| t.open(path, FileMode::ReadWrite, FileAttribute::empty())) {
+        Ok(ok) => ok,
+        Err(err) => return Err(err.status())
+    };
+    let info = match file.get_boxed_info::<FileInfo>() {
+        Ok(ok) => ok,
+        Err(err) => return Err(err.status())
+    };
+
+    let new_path_string = new_path.to_string();
+    let new_name = match CString16::try_from(new_path_string.rsplit('\\').next().unwrap_or_default()) {
+        Ok(ok) => ok,
+        Err(_) => return Err(Status::INVALID_PARAMETER)
+    };
+    // The fixed-size fields of a FileInfo take up 80 bytes, with some slack for alignment
+    let mut storage = vec![0 as u8; 88 + new_name.num_bytes()];
+    let new_info = match FileInfo::new(
+        &mut storage,
+        info.file_size(),
+        info.physical_size(),
+        *info.create_time(),
+        *info.last_access_time(),
+        *info.modification_time(),
+        info.attribute(),
+        &new_name
+    ) {
+        Ok(ok) => ok,
+        Err(_) => return Err(Status::BUFFER_TOO_SMALL)
+    };
+
+    match file.set_info(new_info) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.status())
+    }
+}
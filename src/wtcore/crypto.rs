@@ -0,0 +1,143 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The length of a SHA-256 digest in bytes.
+pub const SHA256_LENGTH: usize = 32;
+
+/// The initial hash values of SHA-256.
+const SHA256_INITIAL: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+];
+
+/// The round constants of SHA-256.
+const SHA256_ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+/// Computes the SHA-256 digest of a message.
+/// [Specification](https://nvlpubs.nist.gov/nistpubs/FIPS/NIST.FIPS.180-4.pdf).
+pub fn sha256(message: &[u8]) -> [u8; SHA256_LENGTH] {
+    // Pad the message to a multiple of 64 bytes, ending with its length in bits
+    let mut padded: Vec<u8> = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((message.len() as u64) * 8).to_be_bytes());
+
+    let mut state = SHA256_INITIAL;
+    for block in padded.chunks_exact(64) {
+        let mut schedule = [0 as u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            schedule[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = schedule[i-15].rotate_right(7) ^ schedule[i-15].rotate_right(18) ^ (schedule[i-15] >> 3);
+            let s1 = schedule[i-2].rotate_right(17) ^ schedule[i-2].rotate_right(19) ^ (schedule[i-2] >> 10);
+            schedule[i] = schedule[i-16].wrapping_add(s0).wrapping_add(schedule[i-7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_ROUND_CONSTANTS[i]).wrapping_add(schedule[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+
+    let mut ret = [0 as u8; SHA256_LENGTH];
+    for (i, word) in state.iter().enumerate() {
+        ret[i*4..i*4+4].copy_from_slice(&word.to_be_bytes());
+    }
+    ret
+}
+
+/// The block size of SHA-256 in bytes, used to pad HMAC keys.
+const SHA256_BLOCK_LENGTH: usize = 64;
+
+/// Computes the HMAC-SHA256 of a message.
+/// [Specification](https://www.rfc-editor.org/rfc/rfc2104).
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; SHA256_LENGTH] {
+    // Keys longer than a block are hashed first
+    let mut padded_key = [0 as u8; SHA256_BLOCK_LENGTH];
+    if key.len() > SHA256_BLOCK_LENGTH {
+        padded_key[..SHA256_LENGTH].copy_from_slice(&sha256(key));
+    }
+    else {
+        padded_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner: Vec<u8> = padded_key.iter().map(|t| t ^ 0x36).collect();
+    inner.extend_from_slice(message);
+    let mut outer: Vec<u8> = padded_key.iter().map(|t| t ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+/// Derives a single-block key from a password using PBKDF2-HMAC-SHA256.
+/// [Specification](https://www.rfc-editor.org/rfc/rfc8018#section-5.2).
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; SHA256_LENGTH] {
+    // Only the first block is needed, as the derived key is as long as a digest
+    let mut salt_block = salt.to_vec();
+    salt_block.extend_from_slice(&(1 as u32).to_be_bytes());
+
+    let mut block = hmac_sha256(password, &salt_block);
+    let mut ret = block;
+    for _ in 1..iterations {
+        block = hmac_sha256(password, &block);
+        for (byte, value) in ret.iter_mut().zip(block) {
+            *byte ^= value;
+        }
+    }
+    ret
+}
+
+/// Compares two byte slices in constant time (for slices of equal length), so comparing secrets does not reveal how much of them matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0 as u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Encodes bytes as a lowercase hexadecimal string.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|t| format!("{:02x}", t)).collect()
+}
+
+/// Decodes a hexadecimal string, returning `None` if it is malformed.
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim().as_bytes();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    hex.chunks_exact(2)
+        .map(|t| u8::from_str_radix(core::str::from_utf8(t).ok()?, 16).ok())
+        .collect()
+}
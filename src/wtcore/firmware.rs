@@ -0,0 +1,14 @@
+use uefi::{cstr16, guid};
+use uefi::runtime::{get_variable, VariableVendor};
+
+/// The vendor GUID of the EFI variables owned by Wakatiwai.
+pub const WAKATIWAI_VENDOR: VariableVendor = VariableVendor(guid!("5e0ffb77-519b-4963-9956-a31872f1b5ef"));
+
+/// Checks if the firmware is booting with Secure Boot enabled.
+pub fn secure_boot_enabled() -> bool {
+    match get_variable(cstr16!("SecureBoot"), &VariableVendor::GLOBAL_VARIABLE, &mut [0 as u8; 1]) {
+        Ok((var, _)) => var.first() == Some(&1),
+        // The variable is absent on firmware which does not support Secure Boot
+        Err(_) => false
+    }
+}
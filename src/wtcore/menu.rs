@@ -1,27 +1,43 @@
 extern crate alloc;
 
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use dev::DISK_GUID_HANDLE_MAPPING;
 use uefi::proto::console::text::{Color, Key, ScanCode};
 use uefi::runtime::VariableVendor;
 use uefi::CStr16;
 use wtcore::get_unix_time;
 
 use crate::*;
-use crate::wtcore::config::BootEntry;
+use crate::wtcore::config::{BootEntry, BootGroup, BootItem, MenuMode};
+use crate::boot::volume::clear_volume_id_cache;
+use crate::wtcore::condition::clear_file_exists_cache;
+use crate::dev::DAMAGED_GPT;
+use crate::wtcore::config::load::{loaded_from_fallback, LOADED_FROM_BACKUP, LOADED_FROM_VARIABLE};
+use crate::wtcore::password::prompt_password;
 
 /// Options that can be selected by the boot menu.
 #[derive(Clone)]
 pub enum MenuOption {
     /// A bootable option, backed by a boot entry.
     BootOption(BootEntry),
+    /// A submenu, backed by a group of boot entries.
+    Submenu(BootGroup),
     /// Option to exit the bootloader.
     Exit,
     /// Option to exit to firmware.
     Firmware,
     /// Option to edit the bootloader configuration file.
     EditConfig,
+    /// Option to store the bootloader configuration file in its EFI variable.
+    StoreConfig,
+    /// Option to restore the bootloader configuration file from its EFI variable.
+    RestoreConfig,
+    /// Option to trust the bootloader configuration file by enrolling its hash.
+    TrustConfig,
+    /// Option to make a boot entry the default by moving it to the front of the bootloader configuration file.
+    SetDefault(BootEntry),
     /// Option to reboot the computer
     Reboot,
     /// Option to power off the computer
@@ -35,49 +51,201 @@ impl MenuOption {
     const FIRMWARE_LABEL:  &'static str = "Escape to Firmware";
     #[doc(hidden)]
     const EDIT_CONFIG_LABEL: &'static str = "Edit Bootloader Config";
+    #[doc(hidden)]
+    const STORE_CONFIG_LABEL: &'static str = "Store Config in EFI Variable";
+    #[doc(hidden)]
+    const RESTORE_CONFIG_LABEL: &'static str = "Restore Config from EFI Variable";
+    #[doc(hidden)]
+    const TRUST_CONFIG_LABEL: &'static str = "Trust Current Config";
+
+    /// Returns the label this option is shown with in the menu.
+    fn label(&self) -> &str {
+        match self {
+            MenuOption::BootOption(entry) => &entry.name,
+            MenuOption::Submenu(group) => &group.name,
+            MenuOption::Exit => MenuOption::EXIT_LABEL,
+            MenuOption::Firmware => MenuOption::FIRMWARE_LABEL,
+            MenuOption::EditConfig => MenuOption::EDIT_CONFIG_LABEL,
+            MenuOption::StoreConfig => MenuOption::STORE_CONFIG_LABEL,
+            MenuOption::RestoreConfig => MenuOption::RESTORE_CONFIG_LABEL,
+            MenuOption::TrustConfig => MenuOption::TRUST_CONFIG_LABEL,
+            // Only selected through keys, so never shown
+            MenuOption::SetDefault(_) | MenuOption::Reboot | MenuOption::Poweroff => unreachable!()
+        }
+    }
+
+    /// Checks if selecting this option requires the password, if one is set.
+    fn requires_password(&self) -> bool {
+        match self {
+            MenuOption::BootOption(entry) => entry.locked,
+            MenuOption::Exit |
+            MenuOption::Firmware |
+            MenuOption::EditConfig |
+            MenuOption::StoreConfig |
+            MenuOption::RestoreConfig |
+            MenuOption::TrustConfig |
+            MenuOption::SetDefault(_) => true,
+            _ => false
+        }
+    }
 }
 
+/// Outcomes of waiting for the user to interact with a drawn menu.
+enum MenuSelection {
+    /// An option was selected.
+    Selected(MenuOption),
+    /// The user left the submenu.
+    Back,
+    /// The menu needs to be drawn again because of the user's input.
+    Redraw,
+    /// The disks changed before the user gave any input, so the menu needs to be drawn again without stopping the timeout.
+    Rescanned
+}
+
+/// Determines if hidden boot entries have been revealed by the user.
+static HIDDEN_ENTRIES_REVEALED: AtomicBool = AtomicBool::new(false);
+
 /// A structure describing the boot menu displayed to the user.
 #[derive(Default)]
 pub struct BootMenu {
     menu_options: Vec<MenuOption>,
     current_menu_option_index: usize,
     anchor_start: (usize, usize),
-    anchor_end: (usize, usize)
+    anchor_end: (usize, usize),
+    submenu: bool
 }
 
 impl BootMenu {
     /// Returns a selected option from the boot menu.
     pub fn select_option() -> MenuOption {
-        let mut menu = BootMenu::default();
         let config = CONFIG.read();
 
-        menu.init();
-        let mut focused_option: &MenuOption;
-        if config.timeout == 0 {
+        // The default option is the first boot entry that would be displayed
+        let default_option = config.boot_entries.iter()
+            .find_map(|t| match t {
+                BootItem::Entry(entry) if !entry.hidden && BootMenu::is_entry_visible(entry) => Some(MenuOption::BootOption(entry.clone())),
+                _ => None
+            });
+
+        if config.timeout == 0 && config.menu_mode != MenuMode::HIDDEN {
             // May only instant boot to a boot option
-            if let MenuOption::BootOption(entry) = menu.focus_option(0).unwrap() {
-                return MenuOption::BootOption(entry.clone());
+            match &default_option {
+                Some(some) => {
+                    // If the password is not given, fall back to the menu
+                    if BootMenu::unlock(some) {
+                        return some.clone();
+                    }
+                }
+                None => {
+                    // Theoretically, this should never happen:
+                    eprintln!("Instant boot did not point to a boot entry");
+                }
             }
-            // Theoretically, this should never happen:
-            eprintln!("Instant boot did not point to a boot entry");
         }
 
-        // Timeout markers
-        let mut input_given = false;
-        let target_time = get_unix_time() + config.timeout as i64;
-        if config.timeout < 0 {
-            // Negative timeout implies wait for user input
-            input_given = true;
+        // Negative timeout implies wait for user input
+        let mut target_time = if config.timeout > 0 {
+            Some(get_unix_time() + config.timeout as i64)
         }
+        else {
+            None
+        };
 
-        // Use a locally-scoped variable to avoid confusing focus_option
+        if config.menu_mode == MenuMode::HIDDEN && config.timeout >= 0 {
+            // Only show the menu if a key is pressed (or held) during the grace period
+            let grace_end = get_unix_time() + config.timeout as i64;
+            loop {
+                if let Ok(Some(_)) = stdin!().read_key() {
+                    // The user asked for the menu, so don't time out
+                    target_time = None;
+                    break;
+                }
+                if grace_end <= get_unix_time() {
+                    match default_option {
+                        Some(some) if BootMenu::unlock(&some) => return some,
+                        Some(_) => {
+                            target_time = None;
+                            break;
+                        }
+                        None => {
+                            eprintln!("Hidden menu has no boot entry to boot");
+                            target_time = None;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        // The top-level menu cannot be left, so this always yields an option
+        BootMenu::select_from(&config.boot_entries, None, target_time).unwrap()
+    }
+
+    /// Returns a selected option from a (sub)menu of the given entries and groups, or `None` if the user left the submenu.
+    fn select_from(boot_entries: &[BootItem], group_name: Option<&str>, mut target_time: Option<i64>) -> Option<MenuOption> {
         let mut idx = 0;
-        focused_option = menu.focus_option(0).unwrap();
+        let mut focused_label: Option<String> = None;
+        loop {
+            // (Re)draw the menu, keeping the previously focused option even if options before it appeared or disappeared
+            let mut menu = BootMenu::default();
+            menu.init(boot_entries, group_name);
+            if let Some(label) = &focused_label {
+                idx = menu.menu_options.iter().position(|t| t.label() == label).unwrap_or(idx);
+            }
+            menu.focus_option(idx);
+
+            match menu.select(target_time) {
+                MenuSelection::Selected(MenuOption::Submenu(group)) => {
+                    if let Some(some) = BootMenu::select_from(&group.boot_entries, Some(&group.name), None) {
+                        return Some(some);
+                    }
+                }
+                MenuSelection::Selected(some) => {
+                    // Options the password is not given for redraw the menu
+                    if BootMenu::unlock(&some) {
+                        return Some(some);
+                    }
+                }
+                MenuSelection::Back => {
+                    return None;
+                }
+                MenuSelection::Redraw => {}
+                MenuSelection::Rescanned => {
+                    idx = menu.current_menu_option_index;
+                    focused_label = menu.menu_options.get(idx).map(|t| t.label().to_string());
+                    continue;
+                }
+            }
+
+            // Input has been given, so stop the timeout
+            idx = menu.current_menu_option_index;
+            focused_label = menu.menu_options.get(idx).map(|t| t.label().to_string());
+            target_time = None;
+        }
+    }
+
+    /// Waits for the user to interact with the drawn menu.
+    ///
+    /// If a target time is given and no input is received before it, the focused option is selected.
+    fn select(&mut self, target_time: Option<i64>) -> MenuSelection {
+        // Timeout markers
+        let mut input_given = target_time.is_none();
+        let target_time = target_time.unwrap_or_default();
+
+        // Use a locally-scoped variable to avoid confusing focus_option
+        let mut idx = self.current_menu_option_index;
         loop {
+            // Media inserted while the menu is shown may make removable entries available
+            if dev::block_io_installed() && BootMenu::rescan() {
+                return if input_given { MenuSelection::Redraw } else { MenuSelection::Rescanned };
+            }
+
             if !input_given {
                 if target_time <= get_unix_time() {
-                    return focused_option.clone()
+                    match self.menu_options.get(idx) {
+                        Some(some) => return MenuSelection::Selected(some.clone()),
+                        None => input_given = true
+                    }
                 }
             }
 
@@ -88,30 +256,72 @@ impl BootMenu {
                     if idx > 0 {
                         idx -= 1;
                     }
-                    focused_option = menu.focus_option(idx).unwrap();
+                    self.focus_option(idx);
                 }
                 // Select the next entry if possible
                 Some(Key::Special(ScanCode::DOWN)) => {
                     input_given = true;
-                    if idx < menu.menu_options.len()-1 {
+                    if idx + 1 < self.menu_options.len() {
                         idx += 1;
                     }
-                    focused_option = menu.focus_option(idx).unwrap();
+                    self.focus_option(idx);
+                }
+                // Open a submenu
+                Some(Key::Special(ScanCode::RIGHT)) => {
+                    input_given = true;
+                    if let Some(MenuOption::Submenu(group)) = self.menu_options.get(idx) {
+                        return MenuSelection::Selected(MenuOption::Submenu(group.clone()));
+                    }
+                }
+                // Return to the parent menu
+                Some(Key::Special(ScanCode::ESCAPE)) |
+                Some(Key::Special(ScanCode::LEFT)) => {
+                    input_given = true;
+                    if self.submenu {
+                        return MenuSelection::Back;
+                    }
                 }
                 // Reboot if the F5 Key is pressed
                 Some(Key::Special(ScanCode::FUNCTION_5)) => {
-                    return MenuOption::Reboot;
+                    return MenuSelection::Selected(MenuOption::Reboot);
                 }
                 // Power off if the F12 Key is pressed
                 Some(Key::Special(ScanCode::FUNCTION_12)) => {
-                    return MenuOption::Poweroff;
+                    return MenuSelection::Selected(MenuOption::Poweroff);
                 }
                 // Boot the given entry
                 Some(Key::Printable(key)) => {
                     match u16::from(key) as u8 {
                         b' ' | b'\r' => {
-                            return focused_option.clone();
+                            input_given = true;
+                            if let Some(some) = self.menu_options.get(idx) {
+                                return MenuSelection::Selected(some.clone());
+                            }
                         },
+                        // Make the focused boot entry the default
+                        b'd' | b'D' => {
+                            input_given = true;
+                            if let Some(MenuOption::BootOption(entry)) = self.menu_options.get(idx) {
+                                // Only entries of the config file itself can be moved, and hidden entries are never booted by default
+                                if !self.submenu && CONFIG.read().edit_config && !entry.generated && !entry.hidden {
+                                    return MenuSelection::Selected(MenuOption::SetDefault(entry.clone()));
+                                }
+                            }
+                        }
+                        // Rescan the disks
+                        b'r' | b'R' => {
+                            input_given = true;
+                            if BootMenu::rescan() {
+                                return MenuSelection::Redraw;
+                            }
+                        }
+                        // Reveal hidden entries
+                        b'\t' => {
+                            if !HIDDEN_ENTRIES_REVEALED.swap(true, Ordering::Relaxed) {
+                                return MenuSelection::Redraw;
+                            }
+                            input_given = true;
+                        }
                         _ => {
                             input_given = true;
                         }
@@ -125,29 +335,32 @@ impl BootMenu {
         }
     }
 
-    /// Draws and initialises the boot menu.
-    fn init(&mut self) {
+    /// Draws and initialises the boot menu for the given entries and groups.
+    ///
+    /// If a group name is given, the menu is drawn as a submenu of that name.
+    fn init(&mut self, boot_entries: &[BootItem], group_name: Option<&str>) {
         let config = CONFIG.read();
+        self.submenu = group_name.is_some();
 
         // Check if firmware reboot is supported
-        let firmware_reboot_supported: bool;
-        match get_variable(cstr16!("OsIndicationsSupported"), &VariableVendor::GLOBAL_VARIABLE, &mut [0 as u8; 8]) {
-            Ok((var, _)) => {
-                // Check if the fwui bit is set
-                let os_indications_supported = u64::from_ne_bytes(var.try_into().unwrap());
-                if os_indications_supported & 0b1 == 0 {
-                    wprintln!("Firmware UI reboot is unsupported");
-                    firmware_reboot_supported = false;
+        let mut firmware_reboot_supported = false;
+        if !self.submenu {
+            match get_variable(cstr16!("OsIndicationsSupported"), &VariableVendor::GLOBAL_VARIABLE, &mut [0 as u8; 8]) {
+                Ok((var, _)) => {
+                    // Check if the fwui bit is set
+                    let os_indications_supported = u64::from_ne_bytes(var.try_into().unwrap());
+                    if os_indications_supported & 0b1 == 0 {
+                        wprintln!("Firmware UI reboot is unsupported");
+                    }
+                    else {
+                        firmware_reboot_supported = true;
+                    }
                 }
-                else {
-                    firmware_reboot_supported = true;
+                Err(_) => {
+                    // Unable to determine if the firmware supports fwui reboot
+                    wprintln!("Missing EFI variable: OsIndicationsSupported");
                 }
             }
-            Err(_) => {
-                // Unable to determine if the firmware supports fwui reboot
-                wprintln!("Missing EFI variable: OsIndicationsSupported");
-                firmware_reboot_supported = false;
-            }
         }
 
         // Clear menu if told to do so
@@ -155,28 +368,67 @@ impl BootMenu {
             stdout!().clear().unwrap();
         }
 
-        println_force!(" Wakatiwai Bootloader |");
-        println_force!("=#====================|");
-        for entry in &config.boot_entries {
-            if entry.removable {
-                if !DISK_GUID_HANDLE_MAPPING.contains_key(&entry.disk_guid) {
-                    continue;
-                }
-            }
-            println_force!(" #-> {}", entry.name);
-            self.menu_options.push(MenuOption::BootOption(entry.clone()));
+        // Make sure the user knows their config is not the one being used
+        if !self.submenu && LOADED_FROM_BACKUP.load(Ordering::Relaxed) {
+            wprintln_force!("!! The config file could not be loaded, using the last-known-good backup !!");
         }
-        if config.firmware && firmware_reboot_supported {
-            println_force!(" #-$ {}", MenuOption::FIRMWARE_LABEL);
-            self.menu_options.push(MenuOption::Firmware);
+        if !self.submenu && LOADED_FROM_VARIABLE.load(Ordering::Relaxed) {
+            wprintln_force!("!! The config file could not be loaded, using the copy in the EFI variable !!");
+        }
+        if !self.submenu && DAMAGED_GPT.load(Ordering::Relaxed) {
+            wprintln_force!("!! The GPT of a disk is damaged and should be repaired from the OS !!");
+        }
+
+        match group_name {
+            Some(some) => {
+                println_force!(" {} |", some);
+                println_force!("=#{}|", "=".repeat(some.len()));
+            }
+            None => {
+                println_force!(" Wakatiwai Bootloader |");
+                println_force!("=#====================|");
+            }
         }
-        if config.exit {
-            println_force!(" #-! {}", MenuOption::EXIT_LABEL);
-            self.menu_options.push(MenuOption::Exit);
+        let reveal_hidden = HIDDEN_ENTRIES_REVEALED.load(Ordering::Relaxed);
+        for boot_item in boot_entries {
+            match boot_item {
+                BootItem::Entry(entry) => {
+                    if (entry.hidden && !reveal_hidden) || !BootMenu::is_entry_visible(entry) {
+                        continue;
+                    }
+                    println_force!(" #-> {}", entry.name);
+                    self.menu_options.push(MenuOption::BootOption(entry.clone()));
+                }
+                BootItem::Group(group) => {
+                    println_force!(" #-+ {}", group.name);
+                    self.menu_options.push(MenuOption::Submenu(group.clone()));
+                }
+            }
         }
-        if config.edit_config {
-            println_force!(" #-@ {}", MenuOption::EDIT_CONFIG_LABEL);
-            self.menu_options.push(MenuOption::EditConfig);
+        if !self.submenu {
+            if config.firmware && firmware_reboot_supported {
+                println_force!(" #-$ {}", MenuOption::FIRMWARE_LABEL);
+                self.menu_options.push(MenuOption::Firmware);
+            }
+            if config.exit {
+                println_force!(" #-! {}", MenuOption::EXIT_LABEL);
+                self.menu_options.push(MenuOption::Exit);
+            }
+            if config.edit_config {
+                println_force!(" #-@ {}", MenuOption::EDIT_CONFIG_LABEL);
+                self.menu_options.push(MenuOption::EditConfig);
+            }
+            if config.edit_config && (config.nv_config || LOADED_FROM_VARIABLE.load(Ordering::Relaxed)) {
+                println_force!(" #-@ {}", MenuOption::STORE_CONFIG_LABEL);
+                self.menu_options.push(MenuOption::StoreConfig);
+                println_force!(" #-@ {}", MenuOption::RESTORE_CONFIG_LABEL);
+                self.menu_options.push(MenuOption::RestoreConfig);
+            }
+            // Only the config file itself may be trusted, not a fallback copy loaded in its place
+            if config.edit_config && !loaded_from_fallback() {
+                println_force!(" #-@ {}", MenuOption::TRUST_CONFIG_LABEL);
+                self.menu_options.push(MenuOption::TrustConfig);
+            }
         }
         
         // Set anchor_start after anchor_end since the menu might cause the screen to scroll - this ensures validity
@@ -185,6 +437,31 @@ impl BootMenu {
         self.current_menu_option_index = 0;
     }
 
+    /// Prompts for the password if the option requires it, returning `true` if the option may be selected.
+    fn unlock(option: &MenuOption) -> bool {
+        match &CONFIG.read().password {
+            Some(password) if option.requires_password() => prompt_password(password),
+            _ => true
+        }
+    }
+
+    /// Rescans the disks, returning `true` if they have changed and the menu must be redrawn.
+    fn rescan() -> bool {
+        if !dev::rescan_disks() {
+            return false;
+        }
+
+        // Anything learned about the previous disks may no longer hold
+        clear_file_exists_cache();
+        clear_volume_id_cache();
+        true
+    }
+
+    /// Checks if a boot entry should be offered in the menu.
+    fn is_entry_visible(entry: &BootEntry) -> bool {
+        entry.condition.evaluate(entry)
+    }
+
     /// Focuses a given menu option.
     fn focus_option(&mut self, index: usize) -> Option<&MenuOption> {
         // Check if index is legal
@@ -235,13 +512,7 @@ impl BootMenu {
             None => return true
         };
         // Get the text of the menu option
-        let option_text = match self.menu_options.get(index).unwrap() {
-            MenuOption::BootOption(entry) => &entry.name,
-            MenuOption::Exit => MenuOption::EXIT_LABEL,
-            MenuOption::Firmware => MenuOption::FIRMWARE_LABEL,
-            MenuOption::EditConfig => MenuOption::EDIT_CONFIG_LABEL,
-            _ => unreachable!()
-        };
+        let option_text = self.menu_options.get(index).unwrap().label();
 
         // Overwrite the menu option's label in a new colour
         stdout!().set_cursor_position(target.0, target.1).unwrap();
@@ -1,8 +1,13 @@
 use core::str::FromStr;
 
+pub mod condition;
 pub mod config;
+pub mod crypto;
+pub mod firmware;
 pub mod menu;
+pub mod options;
 pub mod panic;
+pub mod password;
 pub mod print;
 
 /// Shorthand to get the loaded image handle.
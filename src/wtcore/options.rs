@@ -0,0 +1,170 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str::FromStr;
+
+use spin::Lazy;
+use uefi::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::{CString16, Guid};
+
+use crate::*;
+use crate::wtcore::config::{FileLocation, LogLevel};
+
+/// The path of a configuration file on another partition, if none is given.
+const DEFAULT_CONFIG_LOCATION_PATH: &str = "/EFI/wakatiwai/wtconfig.json";
+
+/// The overrides passed to the bootloader through its load options.
+///
+/// Load options are read once, before the configuration file is loaded. Messages printed here
+/// must not depend on the configuration, as it may be locked or not yet loaded.
+pub static LOAD_OPTIONS: Lazy<LoadOptions> = Lazy::new(LoadOptions::read);
+
+/// Describes the overrides which may be passed to the bootloader through its load options,
+/// e.g. `config=\EFI\wakatiwai\other.json loglevel=DEBUG timeout=0`.
+///
+/// A configuration file on another partition is given by `configfrom=<disk GUID>:<partition>:<file system>`,
+/// with `config` giving its path on that partition.
+#[derive(Debug, Default)]
+pub struct LoadOptions {
+    /// The path of the configuration file to load instead of the default.
+    pub config_path: Option<CString16>,
+    /// The location of a configuration file on another partition to load instead of the default.
+    pub config_location: Option<FileLocation>,
+    /// The log level to use instead of the configured one.
+    pub log_level: Option<LogLevel>,
+    /// The timeout to use instead of the configured one.
+    pub timeout: Option<i32>,
+}
+
+impl LoadOptions {
+    #[doc(hidden)]
+    const KEY_CONFIG: &'static str = "config";
+    #[doc(hidden)]
+    const KEY_CONFIG_FROM: &'static str = "configfrom";
+    #[doc(hidden)]
+    const KEY_LOG_LEVEL: &'static str = "loglevel";
+    #[doc(hidden)]
+    const KEY_TIMEOUT: &'static str = "timeout";
+
+    /// Reads the load options of the bootloader's loaded image.
+    fn read() -> Self {
+        let loaded_image: ScopedProtocol<LoadedImage>;
+        unsafe {
+            match uefi::boot::open_protocol::<LoadedImage>(
+                OpenProtocolParams {
+                    handle: image_handle!(),
+                    agent: image_handle!(),
+                    controller: None
+                },
+                OpenProtocolAttributes::GetProtocol
+            ) {
+                Ok(ok) => {
+                    loaded_image = ok;
+                }
+                Err(_) => {
+                    return LoadOptions::default();
+                }
+            }
+        }
+
+        match loaded_image.load_options_as_bytes() {
+            Some(some) => LoadOptions::parse(&decode_ucs2(some)),
+            None => LoadOptions::default()
+        }
+    }
+
+    /// Parses whitespace-separated `key=value` options.
+    ///
+    /// Anything which is not a `key=value` pair (such as the image path passed by the UEFI shell) is ignored.
+    fn parse(options: &str) -> Self {
+        let mut ret = LoadOptions::default();
+        let mut config_value: Option<&str> = None;
+
+        for option in options.split_ascii_whitespace() {
+            let (key, value) = match option.split_once('=') {
+                Some(some) => some,
+                None => continue
+            };
+
+            match key {
+                LoadOptions::KEY_CONFIG => {
+                    config_value = Some(value);
+                    match CString16::try_from(value) {
+                        Ok(ok) => ret.config_path = Some(ok),
+                        Err(_) => {
+                            eprintln_force!("Invalid config path in load options: {}", value);
+                        }
+                    }
+                }
+                LoadOptions::KEY_CONFIG_FROM => {
+                    match parse_location(value) {
+                        Some(some) => ret.config_location = Some(some),
+                        None => {
+                            eprintln_force!("Invalid config location in load options: {}", value);
+                        }
+                    }
+                }
+                LoadOptions::KEY_LOG_LEVEL => {
+                    match value {
+                        "SILENT" | "QUIET" | "NORMAL" | "DEBUG" => {
+                            ret.log_level = LogLevel::from_str(value).ok();
+                        }
+                        _ => {
+                            eprintln_force!("Invalid log level in load options: {}", value);
+                        }
+                    }
+                }
+                LoadOptions::KEY_TIMEOUT => {
+                    match value.parse::<i32>() {
+                        Ok(ok) => ret.timeout = Some(ok),
+                        Err(_) => {
+                            eprintln_force!("Invalid timeout in load options: {}", value);
+                        }
+                    }
+                }
+                _ => {
+                    wprintln_force!("Ignoring unknown load option \"{}\"", key);
+                }
+            }
+        }
+
+        // A config on another partition takes the config path as its path
+        if let Some(location) = &mut ret.config_location {
+            location.path = match config_value {
+                Some(some) => some.replace('\\', "/"),
+                None => String::from(DEFAULT_CONFIG_LOCATION_PATH)
+            };
+            ret.config_path = None;
+        }
+
+        ret
+    }
+}
+
+/// Parses a `<disk GUID>:<partition>:<file system>` location, where an empty disk GUID refers to the bootloader's disk.
+fn parse_location(value: &str) -> Option<FileLocation> {
+    let mut parts = value.splitn(3, ':');
+    let (disk_guid, partition, fstype) = (parts.next()?, parts.next()?, parts.next()?);
+
+    Some(FileLocation {
+        disk_guid: if disk_guid.is_empty() { *dev::BOOTLOADER_DISK_GUID } else { Guid::from_str(disk_guid).ok()? },
+        disk_signature: 0,
+        partition: partition.parse().ok()?,
+        fstype: String::from(fstype),
+        path: String::new()
+    })
+}
+
+/// Decodes NUL-terminated UCS-2 load options, as set by the firmware's boot options or `efibootmgr -u`.
+fn decode_ucs2(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2)
+        .map(|t| u16::from_le_bytes([t[0], t[1]]))
+        .take_while(|t| *t != 0)
+        .collect();
+
+    char::decode_utf16(units)
+        .map(|t| t.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
@@ -0,0 +1,137 @@
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Display;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::ResultExt;
+
+use crate::*;
+use crate::wtcore::crypto::{constant_time_eq, decode_hex, encode_hex, pbkdf2_sha256, SHA256_LENGTH};
+
+/// The number of attempts the user has to enter the correct password.
+const MAX_ATTEMPTS: usize = 3;
+/// The longest delay after an incorrect password, in seconds.
+const MAX_DELAY: usize = 300;
+
+/// The number of incorrect passwords entered across all prompts since the bootloader was started.
+static FAILED_ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A salted password hash, written as `pbkdf2-sha256$<iterations>$<salt>$<hash>` with the salt and hash hex-encoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordHash {
+    /// The number of PBKDF2 iterations.
+    pub iterations: u32,
+    /// The salt the password is hashed with.
+    pub salt: Vec<u8>,
+    /// The derived key of the password.
+    pub hash: [u8; SHA256_LENGTH],
+}
+
+impl PasswordHash {
+    #[doc(hidden)]
+    const SCHEME: &'static str = "pbkdf2-sha256";
+
+    /// Parses a password hash, returning `None` if it is malformed or uses an unsupported scheme.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split('$');
+        if parts.next()? != PasswordHash::SCHEME {
+            return None;
+        }
+        let iterations: u32 = parts.next()?.parse().ok()?;
+        let salt = decode_hex(parts.next()?)?;
+        let hash = decode_hex(parts.next()?)?.try_into().ok()?;
+        if iterations == 0 || parts.next().is_some() {
+            return None;
+        }
+
+        Some(PasswordHash { iterations, salt, hash })
+    }
+
+    /// Checks if a password matches this hash.
+    pub fn verify(&self, password: &str) -> bool {
+        constant_time_eq(&pbkdf2_sha256(password.as_bytes(), &self.salt, self.iterations), &self.hash)
+    }
+}
+
+impl Display for PasswordHash {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}${}${}${}", PasswordHash::SCHEME, self.iterations, encode_hex(&self.salt), encode_hex(&self.hash))
+    }
+}
+
+/// Prompts the user for a password until it matches the given hash or they run out of attempts.
+///
+/// Returns `true` if the correct password was entered, or `false` if the user gave up or ran out of attempts.
+/// Once the user has run out of attempts, each further incorrect password doubles the delay before they may try again, even at a new prompt.
+pub fn prompt_password(hash: &PasswordHash) -> bool {
+    println_force!("");
+    for attempt in 1..=MAX_ATTEMPTS {
+        let password = match read_masked_line("Password: ") {
+            Some(some) => some,
+            None => return false
+        };
+
+        if hash.verify(&password) {
+            return true;
+        }
+        eprintln_force!("Incorrect password ({} of {} attempts)", attempt, MAX_ATTEMPTS);
+
+        // Slow down guessing, increasingly so once the user has run out of attempts
+        let failures = FAILED_ATTEMPTS.fetch_add(1, Ordering::Relaxed) + 1;
+        let delay = if failures < MAX_ATTEMPTS {
+            1
+        }
+        else {
+            1usize.checked_shl((failures - MAX_ATTEMPTS + 1) as u32).unwrap_or(MAX_DELAY).min(MAX_DELAY)
+        };
+        if delay > 1 {
+            eprintln_force!("Too many incorrect passwords, wait {} seconds before trying again", delay);
+        }
+        uefi::boot::stall(delay * 1_000_000);
+    }
+
+    false
+}
+
+/// Reads a line from the user, echoing each character as `*`.
+///
+/// Returns `None` if the user pressed the Escape key.
+fn read_masked_line(prompt: &str) -> Option<String> {
+    print_force!("{}", prompt);
+    let mut ret = String::new();
+
+    loop {
+        uefi::boot::wait_for_event(
+            [stdin!().wait_for_key_event().unwrap()].as_mut()
+        ).discard_errdata().unwrap();
+
+        match stdin!().read_key().unwrap() {
+            Some(Key::Special(ScanCode::ESCAPE)) => {
+                println_force!("");
+                return None;
+            }
+            Some(Key::Printable(key)) => {
+                match char::from(key) {
+                    '\r' | '\n' => {
+                        println_force!("");
+                        return Some(ret);
+                    }
+                    // Backspace
+                    '\u{8}' => {
+                        if ret.pop().is_some() {
+                            print_force!("\u{8} \u{8}");
+                        }
+                    }
+                    c => {
+                        ret.push(c);
+                        print_force!("*");
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}